@@ -1,24 +1,24 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use rand::random;
-use tsunami::{binary, CanvasSize, Proto};
+use tsunami::{binary, CanvasSize, Encoding, Proto, Region};
 
 pub fn benchmark_send(c: &mut Criterion) {
     c.bench_function("send bin frame", |b| {
         async fn fun_name(
-            (mut proto, canvas, size, mut writer): (binary::Protocol, u8, CanvasSize, Vec<u8>),
+            (mut proto, canvas, region, mut writer): (binary::Protocol, u8, Region, Vec<u8>),
         ) {
             let color = random();
             proto
-                .send_frame(&mut writer, canvas, color, &size)
+                .send_frame(&mut writer, canvas, color, &region, &Encoding::default())
                 .await
                 .expect("should not fail");
         }
         b.iter_batched(
             || {
-                let proto = binary::Protocol { count: 0 };
-                let size = criterion::black_box(CanvasSize { x: 800, y: 600 });
+                let proto = binary::Protocol { buf: Vec::new() };
+                let region = std::hint::black_box(Region::from(&CanvasSize { x: 800, y: 600 }));
                 let canvas = random();
-                (proto, canvas, size, Vec::new())
+                (proto, canvas, region, Vec::new())
             },
             fun_name,
             BatchSize::SmallInput,
@@ -29,19 +29,19 @@ pub fn benchmark_send(c: &mut Criterion) {
 pub fn benchmark_receive(c: &mut Criterion) {
     c.bench_function("receive bin frame", |b| {
         async fn fun_name(
-            (mut proto, canvas, size, mut writer): (binary::Protocol, u8, CanvasSize, Vec<u8>),
+            (mut proto, canvas, region, mut writer): (binary::Protocol, u8, Region, Vec<u8>),
         ) {
             proto
-                .get_frame(&mut writer, canvas, &size)
+                .get_frame(&mut writer, canvas, &region, &Encoding::default())
                 .await
                 .expect("should not fail");
         }
         b.iter_batched(
             || {
-                let proto = binary::Protocol { count: 0 };
-                let size = criterion::black_box(CanvasSize { x: 800, y: 600 });
+                let proto = binary::Protocol { buf: Vec::new() };
+                let region = std::hint::black_box(Region::from(&CanvasSize { x: 800, y: 600 }));
                 let canvas = random();
-                (proto, canvas, size, Vec::new())
+                (proto, canvas, region, Vec::new())
             },
             fun_name,
             BatchSize::SmallInput,
@@ -1,17 +1,115 @@
+use std::{ffi::OsString, path::PathBuf};
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use clap_serde_derive::ClapSerde;
 use serde::{Deserialize, Serialize};
 
-use crate::{Mode, Protocol};
+use crate::{Mode, PixelOrder, Protocol, Transport};
 
-#[derive(ClapSerde, Clone, Debug, Serialize, Deserialize)]
+#[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub args: <Args as ClapSerde>::Opt,
+
+    #[command(flatten)]
+    negations: Negations,
+}
+
+// `--no-*` forms of the flags, to turn off one that the config file turned on. A
+// plain comment, clap would take a doc comment as the about of the whole command.
+#[derive(clap::Args)]
+struct Negations {
+    /// Send binary websocket messages, even if the config says text
+    #[clap(long, overrides_with = "ws_text")]
+    no_ws_text: bool,
+
+    /// Do not wrap the connection in TLS, even if the config does
+    #[clap(long, overrides_with = "tls")]
+    no_tls: bool,
+
+    /// Draw with full coordinates, even if the config uses OFFSET
+    #[clap(long, overrides_with = "offset")]
+    no_offset: bool,
+
+    /// Send grays as `rrggbb`, even if the config uses the short form
+    #[clap(long, overrides_with = "short_gray")]
+    no_short_gray: bool,
+
+    /// Leave the drawn region as it is, even if the config cleans up
+    #[clap(long, overrides_with = "cleanup")]
+    no_cleanup: bool,
+
+    /// Show the usage warning, even if the config acknowledges it
+    #[clap(long, overrides_with = "acknowledge_warning")]
+    no_acknowledge_warning: bool,
+
+    /// Print progress instead of the dashboard, even if the config shows it
+    #[clap(long, overrides_with = "tui")]
+    no_tui: bool,
+
+    /// Disable debug output, even if the config enables it
+    #[clap(long, overrides_with = "debug")]
+    no_debug: bool,
+}
+
+#[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Inspect the config file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigCommand {
+    /// Print the settings a run would use, after the config file and the options
+    Show,
+}
+
+impl Cli {
+    /// Parse the command line `argv`. Options may come before or after a
+    /// subcommand, flags that were not given are left out, instead of setting
+    /// false over the config, and their `--no-*` forms set false.
+    pub fn parse_argv<I, T>(argv: I) -> Cli
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let command = Cli::command().mut_args(|arg| arg.global(true));
+        let mut cli =
+            Cli::from_arg_matches(&command.get_matches_from(argv)).unwrap_or_else(|err| err.exit());
+        let (args, no) = (&mut cli.args, &cli.negations);
+        for (flag, off) in [
+            (&mut args.ws_text, no.no_ws_text),
+            (&mut args.tls, no.no_tls),
+            (&mut args.offset, no.no_offset),
+            (&mut args.short_gray, no.no_short_gray),
+            (&mut args.cleanup, no.no_cleanup),
+            (&mut args.acknowledge_warning, no.no_acknowledge_warning),
+            (&mut args.tui, no.no_tui),
+            (&mut args.debug, no.no_debug),
+        ] {
+            if off {
+                *flag = Some(false);
+            } else if *flag == Some(false) {
+                *flag = None;
+            }
+        }
+        cli
+    }
+}
+
+#[derive(ClapSerde, Clone, Debug, Serialize, Deserialize)]
 pub struct Args {
     /// Target section from config file to use
     #[clap(long)]
     #[serde(skip_serializing)]
     pub target: Option<String>,
 
-    /// Host to connect to
+    /// Host to connect to, as host:port, a ws:// or wss:// url, or unix:/path
     #[clap(long)]
     #[serde(skip_serializing)]
     pub host: Option<String>,
@@ -21,6 +119,41 @@ pub struct Args {
     #[serde(default)]
     pub protocol: Protocol,
 
+    /// Transport to send commands over
+    #[clap(long)]
+    #[serde(default)]
+    pub transport: Transport,
+
+    /// Largest datagram to send over udp (in bytes) [default: 1472]
+    #[clap(long)]
+    pub mtu: Option<usize>,
+
+    /// Send text websocket messages instead of binary ones
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub ws_text: bool,
+
+    /// Wrap the connection in TLS
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub tls: bool,
+
+    /// PEM file with the CA certificates to trust for TLS [default: the Mozilla roots]
+    #[clap(long)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Server name to send and verify for TLS [default: the host name]
+    #[clap(long)]
+    pub tls_sni: Option<String>,
+
+    /// Canvas width, for transports without replies [default: asked over tcp]
+    #[clap(long)]
+    pub canvas_width: Option<u16>,
+
+    /// Canvas height, for transports without replies [default: asked over tcp]
+    #[clap(long)]
+    pub canvas_height: Option<u16>,
+
     /// Wether to send or receive frames
     #[clap(long)]
     #[serde(default)]
@@ -31,6 +164,57 @@ pub struct Args {
     #[serde(default)]
     pub canvas: u8,
 
+    /// Draw in tiles with OFFSET and short coordinates, if the server knows it (plaintext only)
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub offset: bool,
+
+    /// Send opaque grays as `PX x y ww`, which not every server accepts (plaintext only)
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub short_gray: bool,
+
+    /// Order to draw and read the pixels of each frame in
+    #[clap(long)]
+    #[serde(default)]
+    pub order: PixelOrder,
+
+    /// Seed for the random pixel order [default: random]
+    #[clap(long)]
+    pub order_seed: Option<u64>,
+
+    /// Most pixels per second for each connection [default: no limit, 1000 for flutties]
+    #[clap(long)]
+    pub pixel_rate: Option<u64>,
+
+    /// Most bytes per second for each connection [default: no limit]
+    #[clap(long)]
+    pub byte_rate: Option<u64>,
+
+    /// Most pixels per second across all connections [default: no limit]
+    #[clap(long)]
+    pub total_pixel_rate: Option<u64>,
+
+    /// Most bytes per second across all connections [default: no limit]
+    #[clap(long)]
+    pub total_byte_rate: Option<u64>,
+
+    /// Image file to draw instead of random colours (png, ppm/pam, qoi or jpeg)
+    #[clap(long)]
+    pub image: Option<PathBuf>,
+
+    /// Video file to play through ffmpeg
+    #[clap(long)]
+    pub video: Option<PathBuf>,
+
+    /// Frames per second for video playback [default: 25]
+    #[clap(long)]
+    pub fps: Option<u32>,
+
+    /// Snapshot file to save to or restore from, read mode saves its last frame there (png or ppm)
+    #[clap(long)]
+    pub file: Option<PathBuf>,
+
     /// Horizontal offset (in px)
     #[clap(short)]
     #[serde(default)]
@@ -41,11 +225,11 @@ pub struct Args {
     #[serde(default)]
     pub y_offset: usize,
 
-    /// Width (in px) [default: same as source]
+    /// Width (in px) [default: same as source, or up to the canvas edge]
     #[clap(long)]
     pub width: Option<u16>,
 
-    /// Height (in px) [default: same as source]
+    /// Height (in px) [default: same as source, or up to the canvas edge]
     #[clap(long)]
     pub height: Option<u16>,
 
@@ -53,6 +237,53 @@ pub struct Args {
     #[clap(long)]
     pub send_threads: usize,
 
+    /// Timeout for each step of the connection preamble (in ms) [default: 5000]
+    #[clap(long)]
+    pub preamble_timeout: Option<u64>,
+
+    /// Delay before reconnecting a lost connection (in ms) [default: 100]
+    #[clap(long)]
+    pub reconnect_delay: Option<u64>,
+
+    /// Longest delay between reconnect attempts (in ms) [default: 10000]
+    #[clap(long)]
+    pub reconnect_max_delay: Option<u64>,
+
+    /// Give up after this many failed reconnects in a row [default: no limit]
+    #[clap(long)]
+    pub reconnect_attempts: Option<u32>,
+
+    /// Stop after this many seconds
+    #[clap(long)]
+    pub duration: Option<u64>,
+
+    /// Stop each thread after it sent this many frames
+    #[clap(long)]
+    pub frames: Option<u64>,
+
+    /// How long to wait for threads to finish their frame when stopping (in ms) [default: 5000]
+    #[clap(long)]
+    pub stop_timeout: Option<u64>,
+
+    /// Paint the drawn region black before exiting
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub cleanup: bool,
+
+    /// Skip the usage warning on targets that have `consented = true`
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub acknowledge_warning: bool,
+
+    /// Save a run report to this file (json or csv)
+    #[clap(long)]
+    pub report: Option<PathBuf>,
+
+    /// Show a live dashboard of all workers
+    #[clap(long, action=clap::ArgAction::SetTrue)]
+    #[serde(default)]
+    pub tui: bool,
+
     /// Enable debug output
     #[clap(long, action=clap::ArgAction::SetTrue)]
     #[serde(default)]
@@ -60,6 +291,15 @@ pub struct Args {
 }
 
 impl Args {
+    /// Parse the run settings of the command line `argv`, see `Cli::parse_argv`.
+    pub fn parse_cli<I, T>(argv: I) -> <Args as ClapSerde>::Opt
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        Cli::parse_argv(argv).args
+    }
+
     pub fn config_default() -> Self {
         Self {
             host: None,
@@ -69,8 +309,39 @@ impl Args {
             width: None,
             height: None,
             protocol: Protocol::default(),
+            transport: Transport::default(),
+            mtu: None,
+            ws_text: false,
+            tls: false,
+            tls_ca: None,
+            tls_sni: None,
+            canvas_width: None,
+            canvas_height: None,
             mode: Mode::Write,
             canvas: 0,
+            offset: false,
+            short_gray: false,
+            order: PixelOrder::default(),
+            order_seed: None,
+            pixel_rate: None,
+            byte_rate: None,
+            total_pixel_rate: None,
+            total_byte_rate: None,
+            image: None,
+            video: None,
+            fps: None,
+            file: None,
+            preamble_timeout: None,
+            reconnect_delay: None,
+            reconnect_max_delay: None,
+            reconnect_attempts: None,
+            duration: None,
+            frames: None,
+            stop_timeout: None,
+            cleanup: false,
+            acknowledge_warning: false,
+            report: None,
+            tui: false,
             debug: false,
             send_threads: 4,
         }
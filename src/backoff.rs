@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponential backoff with jitter, for reconnecting workers.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    limit: Option<u32>,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration, limit: Option<u32>) -> Self {
+        Self {
+            min,
+            max,
+            limit,
+            attempts: 0,
+        }
+    }
+
+    /// The delay before the next attempt, or `None` once the limit is reached.
+    ///
+    /// The delay doubles with every attempt up to `max`, and a random part
+    /// of up to half of it is taken off so workers do not reconnect in lockstep.
+    pub fn next_delay<R: Rng>(&mut self, rng: &mut R) -> Option<Duration> {
+        if self.limit.is_some_and(|limit| self.attempts >= limit) {
+            return None;
+        }
+        let delay = self
+            .min
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(self.max);
+        self.attempts += 1;
+        Some(delay.mul_f64(rng.random_range(0.5..=1.0)))
+    }
+
+    /// Failed attempts since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Start over, once a connection has proven to work.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_next_delay() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut backoff = Backoff::new(
+            Duration::from_millis(100),
+            Duration::from_millis(1000),
+            Some(6),
+        );
+        for max in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay(&mut rng).unwrap();
+            assert!(delay >= Duration::from_millis(max / 2));
+            assert!(delay <= Duration::from_millis(max));
+        }
+        assert_eq!(backoff.next_delay(&mut rng), None);
+
+        backoff.reset();
+        assert!(backoff.next_delay(&mut rng).unwrap() <= Duration::from_millis(100));
+    }
+}
@@ -3,6 +3,39 @@ use rand::{distr::StandardUniform, prelude::Distribution};
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Color {
     RGB24(u8, u8, u8),
+    /// Blended over the current pixel by servers that support it
+    RGBA32(u8, u8, u8, u8),
+    Gray8(u8),
+}
+
+impl Color {
+    pub fn rgba(&self) -> (u8, u8, u8, u8) {
+        match *self {
+            Color::RGB24(r, g, b) => (r, g, b, u8::MAX),
+            Color::RGBA32(r, g, b, a) => (r, g, b, a),
+            Color::Gray8(w) => (w, w, w, u8::MAX),
+        }
+    }
+
+    /// The colour with its alpha dropped.
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        let (r, g, b, _) = self.rgba();
+        (r, g, b)
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.rgba().3 == u8::MAX
+    }
+
+    /// Blend this colour over `below`, the result is opaque.
+    pub fn over(&self, below: Color) -> Color {
+        let (r, g, b, a) = self.rgba();
+        let (br, bg, bb) = below.rgb();
+        let mix = |top: u8, bottom: u8| {
+            ((top as u16 * a as u16 + bottom as u16 * (u8::MAX - a) as u16) / u8::MAX as u16) as u8
+        };
+        Color::RGB24(mix(r, br), mix(g, bg), mix(b, bb))
+    }
 }
 
 impl Distribution<Color> for StandardUniform {
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use crate::{paths, Args, Error, Protocol, Result};
 use clap::ValueEnum;
+use clap_serde_derive::ClapSerde;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum, Hash, Default)]
@@ -11,24 +12,47 @@ pub enum Mode {
     #[default]
     Write,
     Spray,
+    /// Save the canvas to a file
+    Snapshot,
+    /// Draw a snapshot back onto the canvas
+    Restore,
+    /// Run a mock server on the host address
+    Serve,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub args: Args,
+    /// Only ever read, the default config has none
     #[serde(default)]
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(skip_serializing)]
     pub targets: HashMap<String, Target>,
 }
 
-#[derive(Hash, Clone, Debug, Serialize, Deserialize)]
+/// A named server, with settings that go over the global `args` and
+/// under the ones given on the command line.
+#[derive(Deserialize)]
 pub struct Target {
     pub host: String,
-    pub protocol: Protocol,
-    pub mode: Mode,
+    /// The owner of this server agreed to it being tested, so
+    /// `acknowledge_warning` may skip the usage warning
     #[serde(default)]
-    pub canvas: u8,
+    pub consented: bool,
+    /// The protocol that `auto` found last time, used when `protocol` is `auto`
+    #[serde(default)]
+    pub detected: Option<Protocol>,
+    /// Any of the settings in `args`
+    #[serde(flatten)]
+    pub args: <Args as ClapSerde>::Opt,
+}
+
+impl Target {
+    /// Move the settings of this target over `args`.
+    pub fn apply(&mut self, args: &mut Args) {
+        args.update(&mut self.args);
+        args.host = Some(self.host.clone());
+    }
 }
 
 impl Config {
@@ -66,6 +90,61 @@ impl Config {
         };
         toml::from_str(&config).map_err(|e| Error::FileParseError(e.to_string()))
     }
+
+    /// The settings for a run: the global args, the settings of the target
+    /// over them, and the command line over both. The settings of the
+    /// target are moved out of the config.
+    pub fn resolve(&mut self, cli: <Args as ClapSerde>::Opt) -> Result<Args> {
+        let mut args = self.args.clone();
+        let name = match &cli.target {
+            Some(target) => target.clone(),
+            None => args.target.clone(),
+        };
+        let mut target = match &name {
+            Some(name) => Some(self.targets.get_mut(name).ok_or_else(|| {
+                Error::InvalidConfig(format!("target '{}' not found in config", name))
+            })?),
+            None => None,
+        };
+        if let Some(target) = &mut target {
+            target.apply(&mut args);
+        }
+        let mut args = args.merge(cli);
+        if let (Protocol::Auto, Some(detected)) =
+            (args.protocol, target.and_then(|target| target.detected))
+        {
+            args.protocol = detected;
+        }
+        Ok(args)
+    }
+
+    /// Whether the usage warning can be skipped for `args`, which needs the
+    /// warning acknowledged and a target whose owner consented. Consent only
+    /// covers the target's own host, not one that `--host` put in its place.
+    pub fn skips_warning(&self, args: &Args) -> bool {
+        args.acknowledge_warning
+            && args
+                .target
+                .as_ref()
+                .and_then(|target| self.targets.get(target))
+                .is_some_and(|target| {
+                    target.consented && args.host.as_deref() == Some(target.host.as_str())
+                })
+    }
+
+    /// Remember the detected protocol of a target, keeping the rest of the file as it is.
+    pub fn cache_detected(target: &str, protocol: Protocol) -> Result<()> {
+        let config = std::fs::read_to_string(paths::config_file())?;
+        let mut document = config
+            .parse::<toml_edit::DocumentMut>()
+            .map_err(|e| Error::FileParseError(e.to_string()))?;
+        let name = protocol
+            .to_possible_value()
+            .expect("protocols are never skipped");
+        document["targets"][target]["detected"] = toml_edit::value(name.get_name());
+        std::fs::write(paths::config_file(), document.to_string())?;
+        Ok(())
+    }
 }
 
 impl Default for Config {
@@ -76,3 +155,94 @@ impl Default for Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{Cli, Command, ConfigCommand};
+
+    const CONFIG: &str = r#"
+[args]
+send_threads = 2
+pixel_rate = 100
+byte_rate = 5000
+tls = true
+
+[targets.local]
+host = "127.0.0.1:1337"
+mode = "spray"
+send_threads = 3
+pixel_rate = 200
+cleanup = true
+"#;
+
+    #[test]
+    fn test_resolve_precedence() {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        let cli = Args::parse_cli(["tsunami", "--target", "local", "--pixel-rate", "300"]);
+        let args = config.resolve(cli).unwrap();
+        assert_eq!(args.host.as_deref(), Some("127.0.0.1:1337"));
+        assert!(matches!(args.mode, Mode::Spray));
+        // target over global
+        assert_eq!(args.send_threads, 3);
+        // command line over target
+        assert_eq!(args.pixel_rate, Some(300));
+        // global when neither sets it, flags left off do not reset it
+        assert_eq!(args.byte_rate, Some(5000));
+        assert!(args.tls);
+        assert!(args.cleanup);
+
+        let cli = Args::parse_cli(["tsunami", "--target", "remote"]);
+        assert!(matches!(config.resolve(cli), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test_case(&["--no-tls", "--no-cleanup"] => (false, false) ; "turned off")]
+    #[test_case(&["--no-tls", "--tls"] => (true, true) ; "last one wins")]
+    #[test_case(&["--tls", "--no-tls"] => (false, true) ; "last one wins off")]
+    fn test_no_flags(flags: &[&str]) -> (bool, bool) {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        let argv = ["tsunami", "--target", "local"].iter().chain(flags);
+        let args = config.resolve(Args::parse_cli(argv)).unwrap();
+        (args.tls, args.cleanup)
+    }
+
+    #[test_case(&["tsunami", "--target", "local", "config", "show"] ; "options first")]
+    #[test_case(&["tsunami", "config", "show", "--target", "local"] ; "options last")]
+    fn test_config_show(argv: &[&str]) {
+        let cli = Cli::parse_argv(argv);
+        assert_eq!(cli.command, Some(Command::Config(ConfigCommand::Show)));
+        assert_eq!(cli.args.target, Some(Some("local".to_string())));
+    }
+
+    #[test_case(true, "local", true => true ; "consented and acknowledged")]
+    #[test_case(false, "local", true => false ; "not consented")]
+    #[test_case(true, "local", false => false ; "not acknowledged")]
+    #[test_case(true, "remote", true => false ; "missing target")]
+    fn test_skips_warning(consented: bool, target: &str, acknowledged: bool) -> bool {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        config.targets.get_mut("local").unwrap().consented = consented;
+        let mut args = config
+            .resolve(Args::parse_cli(["tsunami", "--target", "local"]))
+            .unwrap();
+        args.target = Some(target.to_string());
+        args.acknowledge_warning = acknowledged;
+        config.skips_warning(&args)
+    }
+
+    #[test]
+    fn test_skips_warning_host_override() {
+        let mut config: Config = toml::from_str(&format!("{}consented = true\n", CONFIG)).unwrap();
+        let cli = Args::parse_cli([
+            "tsunami",
+            "--target",
+            "local",
+            "--host",
+            "10.0.0.1:1337",
+            "--acknowledge-warning",
+        ]);
+        let args = config.resolve(cli).unwrap();
+        assert!(!config.skips_warning(&args));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    io::{stdout, Stdout, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Print, Stylize},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+use crate::stats::{human, Counters, Stats};
+
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// Why the dashboard closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Closed {
+    /// All workers finished
+    Finished,
+    /// The user pressed q or escape
+    Quit,
+    /// The user pressed Ctrl-C, which raw mode turns into a key press
+    Interrupted,
+}
+
+/// Show live worker statistics until all workers finished or the user quits.
+///
+/// This blocks, so run it on a thread of its own.
+pub fn run(stats: &Stats, title: &str) -> std::io::Result<Closed> {
+    let mut out = stdout();
+    terminal::enable_raw_mode()?;
+    execute!(out, EnterAlternateScreen, cursor::Hide)?;
+    let res = draw_loop(&mut out, stats, title);
+    execute!(out, cursor::Show, LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    res
+}
+
+fn draw_loop(out: &mut Stdout, stats: &Stats, title: &str) -> std::io::Result<Closed> {
+    let mut previous: Vec<Counters> = stats.workers.iter().map(|w| w.counters()).collect();
+    let mut last_draw = Instant::now();
+    loop {
+        let elapsed = last_draw.elapsed();
+        if elapsed >= REFRESH {
+            let current: Vec<Counters> = stats.workers.iter().map(|w| w.counters()).collect();
+            draw(out, stats, title, &previous, &current, elapsed)?;
+            previous = current;
+            last_draw = Instant::now();
+            if stats.all_finished() {
+                return Ok(Closed::Finished);
+            }
+        }
+        if event::poll(REFRESH.saturating_sub(last_draw.elapsed()))? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && ctrl_c {
+                    return Ok(Closed::Interrupted);
+                }
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                {
+                    return Ok(Closed::Quit);
+                }
+            }
+        }
+    }
+}
+
+fn draw(
+    out: &mut Stdout,
+    stats: &Stats,
+    title: &str,
+    previous: &[Counters],
+    current: &[Counters],
+    elapsed: Duration,
+) -> std::io::Result<()> {
+    let seconds = elapsed.as_secs_f64();
+    let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / seconds;
+    let runtime = stats.started.elapsed().as_secs();
+    queue!(
+        out,
+        Clear(ClearType::All),
+        cursor::MoveTo(0, 0),
+        Print(format!(
+            "{}  {:02}:{:02}:{:02}   {}",
+            title.bold(),
+            runtime / 3600,
+            runtime / 60 % 60,
+            runtime % 60,
+            "q: quit".dim()
+        )),
+        cursor::MoveTo(0, 2),
+        Print(
+            format!(
+                "{:>6}  {:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+                "worker",
+                "state",
+                "frames",
+                "frames/s",
+                "pixels/s",
+                "bytes/s",
+                "reconnects",
+                "errors"
+            )
+            .underlined()
+        ),
+    )?;
+    let mut total_rates = (0.0, 0.0, 0.0);
+    for (i, ((worker, now), before)) in stats.workers.iter().zip(current).zip(previous).enumerate()
+    {
+        let rates = (
+            rate(now.frames, before.frames),
+            rate(now.pixels, before.pixels),
+            rate(now.bytes, before.bytes),
+        );
+        total_rates = (
+            total_rates.0 + rates.0,
+            total_rates.1 + rates.1,
+            total_rates.2 + rates.2,
+        );
+        queue!(
+            out,
+            cursor::MoveTo(0, 3 + i as u16),
+            Print(format!(
+                "{:>6}  {:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+                i,
+                worker.state().to_string(),
+                now.frames,
+                human(rates.0),
+                human(rates.1),
+                human(rates.2),
+                now.reconnects,
+                now.errors
+            )),
+        )?;
+    }
+    let total = stats.total();
+    queue!(
+        out,
+        cursor::MoveTo(0, 3 + stats.workers.len() as u16),
+        Print(
+            format!(
+                "{:>6}  {:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+                "total",
+                "",
+                total.frames,
+                human(total_rates.0),
+                human(total_rates.1),
+                human(total_rates.2),
+                total.reconnects,
+                total.errors
+            )
+            .bold()
+        ),
+    )?;
+    out.flush()
+}
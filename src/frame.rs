@@ -0,0 +1,153 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use image::{
+    codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding},
+    imageops, ExtendedColorType, ImageEncoder, ImageReader, RgbaImage,
+};
+
+use crate::{Color, Error, Result};
+
+/// A decoded picture, stored row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<Color>,
+}
+
+impl Frame {
+    /// Decode an image file, the format is guessed from its contents.
+    pub fn load(path: &Path) -> Result<Self> {
+        let image = ImageReader::open(path)?
+            .with_guessed_format()?
+            .decode()
+            .map_err(|e| Error::FileParseError(format!("{}: {}", path.display(), e)))?
+            .into_rgba8();
+        Self::from_rgba(image.width(), image.height(), image.as_raw())
+    }
+
+    /// Build a frame from packed 8 bit RGB data.
+    pub fn from_rgb(width: u32, height: u32, data: &[u8]) -> Result<Self> {
+        Self::from_packed(width, height, data, "RGB", 3, |c| {
+            Color::RGB24(c[0], c[1], c[2])
+        })
+    }
+
+    /// Build a frame from packed 8 bit RGBA data, only pixels that are
+    /// not fully opaque keep their alpha.
+    pub fn from_rgba(width: u32, height: u32, data: &[u8]) -> Result<Self> {
+        Self::from_packed(width, height, data, "RGBA", 4, |c| match c[3] {
+            u8::MAX => Color::RGB24(c[0], c[1], c[2]),
+            a => Color::RGBA32(c[0], c[1], c[2], a),
+        })
+    }
+
+    fn from_packed(
+        width: u32,
+        height: u32,
+        data: &[u8],
+        layout: &str,
+        channels: usize,
+        color: impl Fn(&[u8]) -> Color,
+    ) -> Result<Self> {
+        let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err(Error::FileParseError(format!(
+                "image of {}x{} is larger than a canvas can be",
+                width, height
+            )));
+        };
+        if data.len() != width as usize * height as usize * channels {
+            return Err(Error::FileParseError(format!(
+                "expected {} bytes of {} data for {}x{}, got {}",
+                width as usize * height as usize * channels,
+                layout,
+                width,
+                height,
+                data.len()
+            )));
+        }
+        let pixels = data.chunks_exact(channels).map(color).collect();
+        Ok(Self {
+            width: w,
+            height: h,
+            pixels,
+        })
+    }
+
+    /// Whether any pixel is not fully opaque.
+    pub fn has_alpha(&self) -> bool {
+        self.pixels.iter().any(|c| !c.is_opaque())
+    }
+
+    /// Resample the frame to `width` x `height`.
+    pub fn scaled(&self, width: u16, height: u16) -> Self {
+        if (width, height) == (self.width, self.height) {
+            return self.clone();
+        }
+        let data = self
+            .pixels
+            .iter()
+            .flat_map(|c| {
+                let (r, g, b, a) = c.rgba();
+                [r, g, b, a]
+            })
+            .collect();
+        let image = RgbaImage::from_raw(self.width as u32, self.height as u32, data)
+            .expect("frame data matches its dimensions");
+        let image = imageops::resize(
+            &image,
+            width as u32,
+            height as u32,
+            imageops::FilterType::Triangle,
+        );
+        Self::from_rgba(image.width(), image.height(), image.as_raw())
+            .expect("scaled frame fits on a canvas")
+    }
+
+    /// Encode the frame to an image file, the format follows from the extension.
+    ///
+    /// Alpha is dropped, snapshots are always opaque.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data: Vec<u8> = self
+            .pixels
+            .iter()
+            .flat_map(|c| {
+                let (r, g, b) = c.rgb();
+                [r, g, b]
+            })
+            .collect();
+        let (width, height) = (self.width as u32, self.height as u32);
+        let is_ppm = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"));
+        let res = if is_ppm {
+            // the pnm encoder defaults to PAM, which most viewers can't open
+            let file = BufWriter::new(File::create(path)?);
+            PnmEncoder::new(file)
+                .with_subtype(PnmSubtype::Pixmap(SampleEncoding::Binary))
+                .write_image(&data, width, height, ExtendedColorType::Rgb8)
+        } else {
+            image::save_buffer(path, &data, width, height, ExtendedColorType::Rgb8)
+        };
+        res.map_err(|e| Error::FileParseError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Copy out the `width` x `height` area starting at (`x`, `y`).
+    pub fn crop(&self, x: u16, y: u16, width: u16, height: u16) -> Self {
+        let width = width.min(self.width.saturating_sub(x));
+        let height = height.min(self.height.saturating_sub(y));
+        let pixels = (y..y + height)
+            .flat_map(|j| (x..x + width).map(move |i| (i, j)))
+            .map(|(i, j)| self.get(i, j))
+            .collect();
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn get(&self, x: u16, y: u16) -> Color {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{CanvasSize, Color, Frame};
+
+/// Canvas contents read back from a server, shared between all workers.
+pub struct Framebuffer {
+    pub width: u16,
+    pub height: u16,
+    pixels: Vec<AtomicU32>,
+}
+
+impl Framebuffer {
+    pub fn new(size: &CanvasSize) -> Self {
+        Self {
+            width: size.x,
+            height: size.y,
+            pixels: (0..size.x as usize * size.y as usize)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+        }
+    }
+
+    /// Store a pixel, coordinates outside of the canvas are ignored and
+    /// transparent colours get blended over what is there.
+    pub fn set(&self, x: u16, y: u16, color: Color) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let color = match color.is_opaque() {
+            true => color,
+            false => color.over(self.get(x, y)),
+        };
+        let (r, g, b) = color.rgb();
+        self.pixels[y as usize * self.width as usize + x as usize]
+            .store(u32::from_be_bytes([0, r, g, b]), Ordering::Relaxed);
+    }
+
+    pub fn get(&self, x: u16, y: u16) -> Color {
+        let [_, r, g, b] = self.pixels[y as usize * self.width as usize + x as usize]
+            .load(Ordering::Relaxed)
+            .to_be_bytes();
+        Color::RGB24(r, g, b)
+    }
+
+    /// Copy the current contents into a frame.
+    pub fn to_frame(&self) -> Frame {
+        let pixels = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.get(x, y))
+            .collect();
+        Frame {
+            width: self.width,
+            height: self.height,
+            pixels,
+        }
+    }
+}
@@ -1,15 +1,34 @@
 mod args;
+mod backoff;
 mod color;
 mod config;
+mod frame;
+mod framebuffer;
+mod limit;
+mod order;
+#[macro_use]
 pub mod protocol;
+mod transport;
 
+pub mod dashboard;
 pub mod paths;
+pub mod server;
+pub mod stats;
+pub mod video;
 use std::fmt::Display;
 
+use clap::ValueEnum;
+
 pub use args::*;
+pub use backoff::*;
 pub use color::*;
 pub use config::*;
+pub use frame::*;
+pub use framebuffer::*;
+pub use limit::*;
+pub use order::*;
 pub use protocol::*;
+pub use transport::*;
 
 #[derive(Debug)]
 pub enum Error {
@@ -18,6 +37,25 @@ pub enum Error {
     FFmpegError(String),
     InvalidArgs(String),
     InvalidConfig(String),
+    /// The server replied with something else than expected
+    UnexpectedResponse {
+        command: String,
+        reply: String,
+    },
+    /// The server did not reply in time
+    Timeout {
+        command: String,
+        after: std::time::Duration,
+    },
+    /// The server rejected a command
+    UnsupportedCommand {
+        command: String,
+        reply: String,
+    },
+    /// The protocol can not draw transparent colours
+    UnsupportedAlpha(Protocol),
+    /// The TLS handshake with the server failed
+    TlsHandshake(String),
     Custom(String),
 }
 
@@ -37,6 +75,30 @@ impl Display for Error {
             Error::FFmpegError(e) => write!(f, "FFmpeg error: {}", e),
             Error::InvalidArgs(e) => write!(f, "Invalid arguments: {}", e),
             Error::InvalidConfig(e) => write!(f, "Invalid config: {}", e),
+            Error::UnexpectedResponse { command, reply } => {
+                write!(f, "Unexpected response to {}: {:?}", command, reply)
+            }
+            Error::Timeout { command, after } => {
+                write!(f, "Timed out after {:?} waiting on {}", after, command)
+            }
+            Error::UnsupportedCommand { command, reply } => {
+                write!(f, "Server does not support {}: {:?}", command, reply)
+            }
+            Error::UnsupportedAlpha(protocol) => {
+                let alpha: Vec<_> = Protocol::value_variants()
+                    .iter()
+                    .filter(|p| **p != Protocol::Auto && p.supports_alpha())
+                    .filter_map(|p| p.to_possible_value())
+                    .map(|p| p.get_name().to_string())
+                    .collect();
+                write!(
+                    f,
+                    "{:?} can not draw transparent colours, use {} instead",
+                    protocol,
+                    alpha.join(" or ")
+                )
+            }
+            Error::TlsHandshake(e) => write!(f, "TLS handshake failed: {}", e),
             Error::Custom(e) => write!(f, "{}", e),
         }
     }
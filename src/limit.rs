@@ -0,0 +1,204 @@
+//! Token buckets that cap how fast commands go out.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncWrite,
+    time::{sleep, Instant, Sleep},
+};
+
+use crate::Framing;
+
+/// Hands out `rate` tokens per second, with bursts of up to 10 ms worth.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        let capacity = (rate / 100.0).max(1.0);
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Take `n` tokens, going into debt when there are not enough. Returns
+    /// how long to wait until the debt is paid off.
+    pub fn take(&self, n: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + (now - *last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now;
+        *tokens -= n as f64;
+        match *tokens >= 0.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64(-*tokens / self.rate),
+        }
+    }
+
+    /// How many tokens to use at once, 1 ms worth keeps the pace smooth.
+    fn slice(&self) -> usize {
+        ((self.rate / 1000.0) as usize).max(1)
+    }
+}
+
+/// The buckets that one connection draws from, its own and the shared ones.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub pixels: Vec<Arc<TokenBucket>>,
+    pub bytes: Vec<Arc<TokenBucket>>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.pixels.is_empty() && self.bytes.is_empty()
+    }
+
+    /// Add a bucket of its own for `pixel_rate` and `byte_rate`, if set.
+    pub fn with_own(&self, pixel_rate: Option<u64>, byte_rate: Option<u64>) -> Limits {
+        let mut limits = self.clone();
+        limits
+            .pixels
+            .extend(pixel_rate.map(|rate| Arc::new(TokenBucket::new(rate))));
+        limits
+            .bytes
+            .extend(byte_rate.map(|rate| Arc::new(TokenBucket::new(rate))));
+        limits
+    }
+
+    /// Take from every bucket, returns the longest wait.
+    fn take(&self, pixels: u64, bytes: u64) -> Duration {
+        let pixels = self.pixels.iter().map(|bucket| bucket.take(pixels));
+        let bytes = self.bytes.iter().map(|bucket| bucket.take(bytes));
+        pixels.chain(bytes).max().unwrap_or_default()
+    }
+}
+
+/// Writes commands no faster than its limits allow, a slice at a time.
+pub struct Throttle<W> {
+    inner: W,
+    framing: Framing,
+    limits: Limits,
+    /// Bytes of a fixed size command that went out already
+    partial: usize,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> Throttle<W> {
+    pub fn new(inner: W, framing: Framing, limits: Limits) -> Self {
+        Self {
+            inner,
+            framing,
+            limits,
+            partial: 0,
+            wait: None,
+        }
+    }
+
+    /// How much of `buf` to write in one go.
+    fn slice(&self, buf: &[u8]) -> usize {
+        let mut len = buf.len();
+        if let Some(slice) = self.limits.bytes.iter().map(|b| b.slice()).min() {
+            len = len.min(slice);
+        }
+        if let Some(slice) = self.limits.pixels.iter().map(|b| b.slice()).min() {
+            let commands = match self.framing {
+                Framing::Lines => buf
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &b)| b == b'\n')
+                    .nth(slice - 1)
+                    .map_or(buf.len(), |(i, _)| i + 1),
+                Framing::Fixed(size) => slice * size - self.partial,
+            };
+            len = len.min(commands);
+        }
+        len.max(1)
+    }
+
+    /// Count the commands that `written` finished.
+    fn commands(&mut self, written: &[u8]) -> u64 {
+        match self.framing {
+            Framing::Lines => written.iter().filter(|&&b| b == b'\n').count() as u64,
+            Framing::Fixed(size) => {
+                let done = (self.partial + written.len()) / size;
+                self.partial = (self.partial + written.len()) % size;
+                done as u64
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttle<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.limits.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if let Some(wait) = &mut this.wait {
+            ready!(wait.as_mut().poll(cx));
+            this.wait = None;
+        }
+        let len = this.slice(buf);
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        let commands = this.commands(&buf[..n]);
+        let wait = this.limits.take(commands, n as u64);
+        if !wait.is_zero() {
+            this.wait = Some(Box::pin(sleep(wait)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_take() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(10), Duration::ZERO);
+        assert_eq!(bucket.take(20), Duration::from_millis(20));
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert_eq!(bucket.take(10), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_pixels() {
+        let limits = Limits::default().with_own(Some(1000), None);
+        let mut writer = Throttle::new(Vec::new(), Framing::Fixed(8), limits);
+        let start = Instant::now();
+        writer.write_all(&[0; 8 * 500]).await.unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(480), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(510), "{:?}", elapsed);
+        assert_eq!(writer.inner.len(), 8 * 500);
+    }
+}
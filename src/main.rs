@@ -1,22 +1,432 @@
-use clap_serde_derive::ClapSerde;
-use std::{io::Write, time::Duration};
+use std::{
+    io::Write,
+    process::ExitCode,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
 use colored::Colorize;
-use rand::{random, SeedableRng};
+use rand::{random, rngs::StdRng, SeedableRng};
 use tokio::{
-    io::{AsyncReadExt, BufReader, BufWriter},
-    net::TcpStream,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpListener,
+    sync::watch,
+    task::{JoinHandle, JoinSet},
     time::sleep,
 };
-use tsunami::*;
+use tsunami::{
+    dashboard,
+    stats::{CountingWriter, Report, Stats, WorkerState, WorkerStats},
+    video::Video,
+    *,
+};
 
 const COUNTDOWN_START_SECONDS: usize = 1;
 const COUNTDOWN_START_SUBSTEPS: usize = 8;
+const DEFAULT_FPS: u32 = 25;
+const DEFAULT_SERVE_SIZE: CanvasSize = CanvasSize { x: 800, y: 600 };
+const DEFAULT_PREAMBLE_TIMEOUT: u64 = 5000;
+const DEFAULT_RECONNECT_DELAY: u64 = 100;
+const DEFAULT_RECONNECT_MAX_DELAY: u64 = 10_000;
+const DEFAULT_STOP_TIMEOUT: u64 = 5000;
+/// What `--cleanup` paints over the drawn region
+const CLEANUP_COLOR: Color = Color::RGB24(0, 0, 0);
+/// Exit code when some threads gave up on the server
+const EXIT_THREADS_FAILED: u8 = 2;
+/// Exit code after Ctrl-C, the same one shells use for SIGINT
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Why the workers were told to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// Every worker finished on its own
+    Done,
+    Duration,
+    Interrupted,
+    /// The dashboard was closed
+    Quit,
+}
+
+/// `println!` for workers, which keep quiet while the dashboard owns the terminal.
+macro_rules! info {
+    ($job:expr, $($arg:tt)*) => {
+        if !$job.quiet {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// `eprintln!` for workers, which keep quiet while the dashboard owns the terminal.
+macro_rules! warn {
+    ($job:expr, $($arg:tt)*) => {
+        if !$job.quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
 
 struct Context {
     args: Args,
 }
 
+/// The settings that all workers share.
+struct Job {
+    endpoint: Endpoint,
+    protocol: Protocol,
+    /// How the commands this mode sends are delimited
+    framing: Framing,
+    mode: Mode,
+    canvas: u8,
+    x_offset: u16,
+    y_offset: u16,
+    width: Option<u16>,
+    height: Option<u16>,
+    threads: usize,
+    preamble_timeout: Duration,
+    /// Known up front when the transport gets no replies
+    size: Option<CanvasSize>,
+    encoding: Encoding,
+    frame_limit: Option<u64>,
+    cleanup: bool,
+    debug: bool,
+    /// The dashboard owns the terminal, so workers do not print
+    quiet: bool,
+    image: Option<Arc<SharedFrame>>,
+    framebuffer: Arc<OnceLock<Framebuffer>>,
+    /// The region as of the last frame that was read in full, kept when read mode saves it
+    last_frame: Option<Mutex<Option<Frame>>>,
+}
+
+/// A single connection worth of work, that survives reconnects.
+struct Worker {
+    thread: usize,
+    job: Arc<Job>,
+    stats: Arc<WorkerStats>,
+    video: Option<watch::Receiver<Option<Arc<SharedFrame>>>>,
+    rng: StdRng,
+    backoff: Backoff,
+    limits: Limits,
+    /// Turns true when all workers should wrap up
+    stop: watch::Receiver<bool>,
+    frames: u64,
+}
+
+impl Worker {
+    /// Work until done, connecting again whenever connecting fails or the
+    /// connection is lost.
+    async fn run<P: Proto>(mut self, proto: &mut P) {
+        loop {
+            let res = match self.job.endpoint.connect(self.job.framing).await {
+                Ok(socket) => {
+                    info!(self.job, "Thread {} connected", self.thread);
+                    self.session(proto, socket).await
+                }
+                Err(err) => Err(err),
+            };
+            let err = match res {
+                Ok(_) => {
+                    self.stats.set_state(WorkerState::Done);
+                    return;
+                }
+                Err(_) if self.stopping() => {
+                    self.stats.set_state(WorkerState::Done);
+                    return;
+                }
+                Err(err) => err,
+            };
+            match self.backoff.next_delay(&mut self.rng) {
+                Some(delay) => {
+                    warn!(
+                        self.job,
+                        "Thread {} has no connection ({}), reconnecting in {} ms",
+                        self.thread,
+                        err,
+                        delay.as_millis()
+                    );
+                    self.stats.reconnecting();
+                    let mut stop = self.stop.clone();
+                    tokio::select! {
+                        _ = sleep(delay) => {}
+                        _ = stop.wait_for(|stop| *stop) => {}
+                    }
+                }
+                None => {
+                    warn!(
+                        self.job,
+                        "Thread {} gave up after {} reconnects: {}",
+                        self.thread,
+                        self.backoff.attempts(),
+                        err
+                    );
+                    self.stats.failed();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Run the preamble on a fresh connection, then keep working on it until
+    /// done, told to stop, or until it breaks.
+    async fn session<P: Proto>(
+        &mut self,
+        proto: &mut P,
+        (reader, writer): (Reader, Writer),
+    ) -> Result<()> {
+        let job = self.job.clone();
+        let thread = self.thread;
+        let canvas = job.canvas;
+        let mut reader = BufReader::new(reader);
+        let writer = CountingWriter::new(writer, self.stats.clone());
+        let mut writer = BufWriter::new(Throttle::new(writer, job.framing, self.limits.clone()));
+        let size = match job.size {
+            Some(size) => size,
+            None => {
+                job.protocol
+                    .preamble(&mut writer, &mut reader, canvas, job.preamble_timeout)
+                    .await?
+            }
+        };
+        info!(
+            job,
+            "Thread {} got canvas size ({}, {})", thread, size.x, size.y
+        );
+        let full = Region::clipped(job.x_offset, job.y_offset, job.width, job.height, &size);
+        // one shot modes and pictures split the work, the others all cover the whole region
+        let region = match job.mode {
+            Mode::Snapshot | Mode::Restore => full.band(thread, job.threads),
+            Mode::Write if job.image.is_some() || self.video.is_some() => {
+                full.band(thread, job.threads)
+            }
+            _ => full,
+        };
+        if region.is_empty() {
+            warn!(
+                job,
+                "Thread {} has nothing to do, its region is empty", thread
+            );
+            return Ok(());
+        }
+        self.stats.set_state(WorkerState::Running);
+        let (read_tx, mut frames_read) = watch::channel(0);
+        let mut read_task = self.spawn_reader(reader, size, region, read_tx);
+        let res = match job.mode {
+            Mode::Read => {
+                let mut sent = 0;
+                let res = loop {
+                    let frame_start = Instant::now();
+                    if let Err(err) = proto
+                        .get_frame(&mut writer, canvas, &region, &job.encoding)
+                        .await
+                    {
+                        break Err(err);
+                    }
+                    sent += 1;
+                    if self.frame_done(&region, frame_start) || self.stopping() {
+                        break writer.flush().await.map_err(Error::from);
+                    }
+                };
+                // the replies lag behind, wait for them so the last frame is read in full
+                if res.is_err() {
+                    res
+                } else if frames_read.wait_for(|&read| read >= sent).await.is_ok() {
+                    Ok(())
+                } else {
+                    // the reader gave up, its error says why
+                    (&mut read_task)
+                        .await
+                        .unwrap_or_else(|err| Err(Error::Custom(err.to_string())))
+                }
+            }
+            Mode::Write => loop {
+                let frame_start = Instant::now();
+                let res = if let Some(video) = &mut self.video {
+                    let (frame, fresh) = {
+                        let frame = video.borrow_and_update();
+                        (frame.clone(), frame.has_changed())
+                    };
+                    // the last frame may come right before the end, draw it first
+                    if !fresh && video.has_changed().is_err() {
+                        info!(job, "Thread {} reached the end of the video", thread);
+                        break Ok(());
+                    }
+                    match frame {
+                        Some(frame) => {
+                            let encoded = frame.encoded(
+                                job.protocol,
+                                canvas,
+                                &full,
+                                &job.encoding,
+                                thread,
+                                job.threads,
+                            )?;
+                            proto.send_encoded(&mut writer, encoded).await
+                        }
+                        None => {
+                            let mut stop = self.stop.clone();
+                            tokio::select! {
+                                _ = video.changed() => continue,
+                                _ = stop.wait_for(|stop| *stop) => break Ok(()),
+                            }
+                        }
+                    }
+                } else if let Some(image) = &job.image {
+                    let encoded = image.encoded(
+                        job.protocol,
+                        canvas,
+                        &full,
+                        &job.encoding,
+                        thread,
+                        job.threads,
+                    )?;
+                    proto.send_encoded(&mut writer, encoded).await
+                } else {
+                    proto
+                        .send_frame(&mut writer, canvas, random(), &region, &job.encoding)
+                        .await
+                };
+                if let Err(err) = res {
+                    break Err(err);
+                }
+                if self.frame_done(&region, frame_start) || self.stopping() {
+                    break Ok(());
+                }
+            },
+            Mode::Spray => loop {
+                let frame_start = Instant::now();
+                let res = proto
+                    .spray_frame(&mut writer, canvas, &mut self.rng, &region, &job.encoding)
+                    .await;
+                if let Err(err) = res {
+                    break Err(err);
+                }
+                if self.frame_done(&region, frame_start) || self.stopping() {
+                    break Ok(());
+                }
+            },
+            Mode::Snapshot => {
+                let frame_start = Instant::now();
+                let res = match proto
+                    .get_frame(&mut writer, canvas, &region, &job.encoding)
+                    .await
+                {
+                    Ok(_) => writer.flush().await.map_err(Error::from),
+                    Err(err) => Err(err),
+                };
+                let res = match res {
+                    Ok(_) => (&mut read_task)
+                        .await
+                        .unwrap_or_else(|err| Err(Error::Custom(err.to_string()))),
+                    Err(err) => Err(err),
+                };
+                res.map(|_| {
+                    self.frame_done(&region, frame_start);
+                })
+            }
+            Mode::Restore => {
+                let frame_start = Instant::now();
+                let image = job
+                    .image
+                    .as_ref()
+                    .expect("restore always loads the snapshot");
+                let encoded = image.encoded(
+                    job.protocol,
+                    canvas,
+                    &full,
+                    &job.encoding,
+                    thread,
+                    job.threads,
+                )?;
+                let res = match proto.send_encoded(&mut writer, encoded).await {
+                    Ok(_) => writer.flush().await.map_err(Error::from),
+                    Err(err) => Err(err),
+                };
+                res.map(|_| {
+                    self.frame_done(&region, frame_start);
+                })
+            }
+            Mode::Serve => unreachable!("serve mode does not connect to anything"),
+        };
+        read_task.abort();
+        res?;
+        if job.cleanup && matches!(job.mode, Mode::Write | Mode::Spray) {
+            let band = full.band(thread, job.threads);
+            proto
+                .send_frame(&mut writer, canvas, CLEANUP_COLOR, &band, &job.encoding)
+                .await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    fn stopping(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    /// Read the responses in the read modes, or throw them away in the others.
+    /// Every frame read in full is counted in `frames_read`.
+    fn spawn_reader(
+        &self,
+        mut reader: BufReader<Reader>,
+        size: CanvasSize,
+        region: Region,
+        frames_read: watch::Sender<u64>,
+    ) -> JoinHandle<Result<()>> {
+        let job = self.job.clone();
+        let thread = self.thread;
+        let (protocol, canvas, mode, debug) = (job.protocol, job.canvas, job.mode, job.debug);
+        match mode {
+            Mode::Read | Mode::Snapshot => tokio::spawn(async move {
+                let framebuffer = job.framebuffer.get_or_init(|| Framebuffer::new(&size));
+                let mut frames: u64 = 0;
+                match_parser!(proto: protocol => {
+                    loop {
+                        match proto.read_frame(&mut reader, canvas, &region, &job.encoding, framebuffer).await {
+                            Ok(_) => {
+                                frames += 1;
+                                frames_read.send_replace(frames);
+                                if debug {
+                                    info!(job, "Thread {} read frame {}", thread, frames);
+                                }
+                                if matches!(mode, Mode::Snapshot) {
+                                    return Ok(());
+                                }
+                                if let Some(last_frame) = &job.last_frame {
+                                    let frame = framebuffer.to_frame().crop(region.x, region.y, region.width, region.height);
+                                    *last_frame.lock().unwrap() = Some(frame);
+                                }
+                            },
+                            Err(err) => {
+                                warn!(job, "reading frames failed on a worker: {}", err);
+                                return Err(err);
+                            },
+                        }
+                    }
+                })
+            }),
+            _ => tokio::spawn(async move {
+                let mut buf = vec![0; 4096];
+                while let Ok(n) = reader.read(&mut buf).await {
+                    if n == 0 {
+                        return Ok(());
+                    }
+                }
+                Ok(())
+            }),
+        }
+    }
+
+    /// Record a finished frame, returns whether the frame limit is reached.
+    fn frame_done(&mut self, region: &Region, frame_start: Instant) -> bool {
+        self.frames += 1;
+        self.stats
+            .frame_done(self.frames, region.pixels(), frame_start.elapsed());
+        self.backoff.reset();
+        self.job
+            .frame_limit
+            .is_some_and(|limit| self.frames >= limit)
+    }
+}
+
 async fn usage_warn() -> bool {
     const USAGE_WARNING: &str = "***** WARNING *****
 Tsunami is a tool designed to stress-test pixelflut servers,
@@ -65,18 +475,109 @@ pub fn verify_args(args: &Args) -> Result<()> {
             "host or target must be specified".to_string(),
         ));
     }
+    if args.image.is_some() && args.video.is_some() {
+        return Err(Error::InvalidArgs(
+            "image and video can not be used together".to_string(),
+        ));
+    }
+    if matches!(args.mode, Mode::Snapshot | Mode::Restore) && args.file.is_none() {
+        return Err(Error::InvalidArgs(
+            "snapshot and restore need a file".to_string(),
+        ));
+    }
+    if matches!(args.mode, Mode::Restore) && args.protocol == Protocol::Palette {
+        return Err(Error::InvalidArgs(
+            "palette can not draw every colour, restore with another protocol".to_string(),
+        ));
+    }
+    if (args.image.is_some() || args.video.is_some()) && args.protocol == Protocol::Palette {
+        return Err(Error::InvalidArgs(
+            "palette can not draw every colour, draw images and videos with another protocol"
+                .to_string(),
+        ));
+    }
+    if args.fps == Some(0) {
+        return Err(Error::InvalidArgs("fps must be greater than 0".to_string()));
+    }
+    if matches!(args.mode, Mode::Serve) && matches!(args.protocol, Protocol::Auto) {
+        return Err(Error::InvalidArgs(
+            "serve mode needs a protocol, auto only works for clients".to_string(),
+        ));
+    }
+    if !args.transport.replies() && matches!(args.mode, Mode::Read | Mode::Snapshot) {
+        return Err(Error::InvalidArgs(
+            "reading needs a transport that gets replies, like tcp".to_string(),
+        ));
+    }
+    if !args.transport.replies() && args.offset {
+        return Err(Error::InvalidArgs(
+            "offset is probed with a reply, it needs a transport like tcp".to_string(),
+        ));
+    }
+    if matches!(args.mode, Mode::Serve) && args.transport != Transport::Tcp {
+        return Err(Error::InvalidArgs(
+            "serve mode only listens on tcp".to_string(),
+        ));
+    }
+    if args.tls && args.transport == Transport::Udp {
+        return Err(Error::InvalidArgs(
+            "tls needs a stream transport, not udp".to_string(),
+        ));
+    }
+    for (name, rate) in [
+        ("pixel_rate", args.pixel_rate),
+        ("byte_rate", args.byte_rate),
+        ("total_pixel_rate", args.total_pixel_rate),
+        ("total_byte_rate", args.total_byte_rate),
+    ] {
+        if rate == Some(0) {
+            return Err(Error::InvalidArgs(format!(
+                "{} must be greater than 0",
+                name
+            )));
+        }
+    }
+    if args.mtu == Some(0) {
+        return Err(Error::InvalidArgs("mtu must be greater than 0".to_string()));
+    }
+    if args.preamble_timeout == Some(0) {
+        return Err(Error::InvalidArgs(
+            "preamble_timeout must be greater than 0".to_string(),
+        ));
+    }
+    if let (Some(delay), Some(max_delay)) = (args.reconnect_delay, args.reconnect_max_delay) {
+        if delay > max_delay {
+            return Err(Error::InvalidArgs(
+                "reconnect_delay can not be longer than reconnect_max_delay".to_string(),
+            ));
+        }
+    }
+    if u16::try_from(args.x_offset).is_err() || u16::try_from(args.y_offset).is_err() {
+        return Err(Error::InvalidArgs(
+            "x_offset and y_offset must fit on a canvas".to_string(),
+        ));
+    }
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    if !usage_warn().await {
-        return Ok(());
+/// Wait for every worker to finish.
+async fn join_all(workers: &mut JoinSet<()>) {
+    while let Some(res) = workers.join_next().await {
+        if let Err(err) = res {
+            println!("handle closed with error {}", err);
+        }
     }
+}
 
-    println!("Loading config");
-    let config = Config::load().unwrap_or_else(
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    let cli = Cli::parse_argv(std::env::args_os());
+    let show_config = cli.command == Some(Command::Config(ConfigCommand::Show));
+    if !show_config {
+        println!("Loading config");
+    }
+    let mut config = Config::load().unwrap_or_else(
     |e| {
             eprintln!("Failed to load config:\n{}", e.to_string().red());
             eprintln!(
@@ -90,108 +591,543 @@ async fn main() -> Result<()> {
 
         });
 
-    println!("Finished loading config");
+    if !show_config {
+        println!("Finished loading config");
+    }
 
-    let mut args = config.args.clone().merge_clap();
+    let mut args = config.resolve(cli.args)?;
+    if let Some(host) = &args.host {
+        args.transport = args.transport.for_host(host);
+        args.tls |= host.starts_with("wss://");
+    }
+    if show_config {
+        if let Some(target) = &args.target {
+            println!("# target: {}", target);
+        }
+        if let Some(host) = &args.host {
+            println!("# host: {}", host);
+        }
+        print!(
+            "{}",
+            toml::to_string(&args).map_err(|e| Error::Custom(e.to_string()))?
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
     verify_args(&args)?;
 
-    if let Some(target) = &args.target {
-        let target = config.targets.get(target).unwrap_or_else(|| {
-            eprintln!("Target '{}' not found in config", target);
-            std::process::exit(1);
-        });
-
-        args.host = Some(target.host.clone());
-        args.protocol = target.protocol;
-        args.canvas = target.canvas;
-        args.mode = target.mode;
+    if matches!(args.mode, Mode::Serve) {
+        // a local mock server floods nobody
+    } else if config.skips_warning(&args) {
+        println!("Skipping the usage warning, the target owner consented");
+    } else {
+        if args.acknowledge_warning {
+            println!("No consent recorded for this host, add `consented = true` to its target to skip the warning");
+        }
+        if !usage_warn().await {
+            return Ok(ExitCode::SUCCESS);
+        }
     }
 
     let context = Context { args };
     let host = context.args.host.clone().unwrap();
-    let protocol = context.args.protocol;
     let mode = context.args.mode;
     let canvas = context.args.canvas;
+    let preamble_timeout = Duration::from_millis(
+        context
+            .args
+            .preamble_timeout
+            .unwrap_or(DEFAULT_PREAMBLE_TIMEOUT),
+    );
+    if let Mode::Serve = mode {
+        let protocol = context.args.protocol;
+        let size = CanvasSize {
+            x: context.args.width.unwrap_or(DEFAULT_SERVE_SIZE.x),
+            y: context.args.height.unwrap_or(DEFAULT_SERVE_SIZE.y),
+        };
+        let listener = TcpListener::bind(&host).await?;
+        println!(
+            "Serving a {}x{} canvas with {:?} on {}",
+            size.x, size.y, protocol, host
+        );
+        server::Server::new(protocol, size).serve(listener).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let endpoint = Endpoint {
+        host: host.clone(),
+        transport: context.args.transport,
+        mtu: context.args.mtu.unwrap_or(DEFAULT_MTU),
+        ws_text: context.args.ws_text,
+        tls: match context.args.tls {
+            true => Some(Tls::new(
+                &host,
+                context.args.tls_ca.as_deref(),
+                context.args.tls_sni.as_deref(),
+            )?),
+            false => None,
+        },
+    };
+    let protocol = match context.args.protocol {
+        Protocol::Auto => {
+            let protocol =
+                Protocol::detect(&endpoint.with_replies(), canvas, preamble_timeout).await?;
+            println!("Detected the {:?} protocol", protocol);
+            if let Some(target) = &context.args.target {
+                if let Err(err) = Config::cache_detected(target, protocol) {
+                    eprintln!("Could not remember the detected protocol: {}", err);
+                }
+            }
+            protocol
+        }
+        protocol => protocol,
+    };
+    let mut encoding = Encoding {
+        offset: false,
+        gray: context.args.short_gray,
+        order: context.args.order,
+        seed: context.args.order_seed.unwrap_or_else(random),
+    };
+    if context.args.offset {
+        encoding.offset = protocol
+            .supports_offset(&endpoint, preamble_timeout)
+            .await?;
+        if !encoding.offset {
+            println!("The server does not know OFFSET, drawing with full coordinates");
+        }
+    }
+    let size = match context.args.transport.replies() {
+        true => None,
+        false => match (context.args.canvas_width, context.args.canvas_height) {
+            (Some(x), Some(y)) => Some(CanvasSize { x, y }),
+            _ => {
+                let size = protocol
+                    .probe(&endpoint.with_replies(), canvas, preamble_timeout)
+                    .await?;
+                println!("Got canvas size ({}, {}) over tcp", size.x, size.y);
+                Some(size)
+            }
+        },
+    };
+    let x_offset = context.args.x_offset as u16;
+    let y_offset = context.args.y_offset as u16;
+    let image_path = match mode {
+        Mode::Restore => &context.args.file,
+        _ => &context.args.image,
+    };
+    let image = match image_path {
+        Some(path) => {
+            let image = Frame::load(path)?;
+            println!("Loaded {}x{} image", image.width, image.height);
+            let image = image.scaled(
+                context.args.width.unwrap_or(image.width),
+                context.args.height.unwrap_or(image.height),
+            );
+            if image.has_alpha() && !protocol.supports_alpha() {
+                return Err(Error::UnsupportedAlpha(protocol));
+            }
+            Some(Arc::new(SharedFrame::new(image)))
+        }
+        None => None,
+    };
+    let (mut width, mut height) = match &image {
+        Some(image) => (Some(image.frame.width), Some(image.frame.height)),
+        None => (context.args.width, context.args.height),
+    };
+    let mut video_task = None;
+    let video = match &context.args.video {
+        Some(path) => {
+            let (video_width, video_height) = match (width, height) {
+                (Some(width), Some(height)) => (width, height),
+                _ => {
+                    let (source_width, source_height) = Video::probe(path).await?;
+                    (
+                        width.unwrap_or(source_width),
+                        height.unwrap_or(source_height),
+                    )
+                }
+            };
+            (width, height) = (Some(video_width), Some(video_height));
+            let fps = context.args.fps.unwrap_or(DEFAULT_FPS);
+            let video = Video::open(path, video_width, video_height, fps)?;
+            println!(
+                "Playing {}x{} video at {} fps",
+                video_width, video_height, fps
+            );
+            let (tx, rx) = watch::channel(None);
+            video_task = Some(tokio::spawn(video.play(tx, fps)));
+            Some(rx)
+        }
+        None => None,
+    };
+    let backoff = Backoff::new(
+        Duration::from_millis(
+            context
+                .args
+                .reconnect_delay
+                .unwrap_or(DEFAULT_RECONNECT_DELAY),
+        ),
+        Duration::from_millis(
+            context
+                .args
+                .reconnect_max_delay
+                .unwrap_or(DEFAULT_RECONNECT_MAX_DELAY),
+        ),
+        context.args.reconnect_attempts,
+    );
+    let limits =
+        Limits::default().with_own(context.args.total_pixel_rate, context.args.total_byte_rate);
+    let pixel_rate = context.args.pixel_rate.or(protocol.max_pixel_rate());
+    let framebuffer = Arc::new(OnceLock::new());
+    let (stop_tx, stop_rx) = watch::channel(false);
 
-    let mut handles = vec![];
     let threads = context.args.send_threads;
+    let stats = Arc::new(Stats::new(threads));
+    let framing = match mode {
+        Mode::Read | Mode::Snapshot => protocol.get_framing(),
+        _ => protocol.framing(),
+    };
+    let job = Arc::new(Job {
+        endpoint: endpoint.clone(),
+        protocol,
+        framing,
+        mode,
+        canvas,
+        x_offset,
+        y_offset,
+        width,
+        height,
+        threads,
+        preamble_timeout,
+        size,
+        encoding,
+        frame_limit: context.args.frames,
+        cleanup: context.args.cleanup,
+        debug: context.args.debug,
+        quiet: context.args.tui,
+        image,
+        framebuffer: framebuffer.clone(),
+        last_frame: (matches!(mode, Mode::Read) && context.args.file.is_some())
+            .then(Default::default),
+    });
+    let mut workers = JoinSet::new();
     println!("Spawning threads");
     for thread in 0..threads {
-        let socket = TcpStream::connect(&host).await?;
-        println!("Thread {} connected", thread);
-        handles.push(tokio::spawn(async move {
-            let (reader, writer) = socket.into_split();
-            let mut reader = BufReader::new(reader);
-            let mut writer = BufWriter::new(writer);
-            let size = protocol
-                .preamble(&mut writer, &mut reader, canvas)
-                .await
-                .unwrap();
-            let read_drain = tokio::spawn(async move {
-                let mut buf = vec![0; 4096];
-                while let Ok(n) = reader.read(&mut buf).await {
-                    if n == 0 {
-                        sleep(Duration::from_millis(10)).await;
-                    }
-                }
-            });
-            println!("Thread {} got canvas size ({}, {})", thread, size.x, size.y);
-            match mode {
-                Mode::Read => {
-                    match_parser!(proto: protocol => {
-                       loop {
-                            match proto.get_frame(&mut writer, canvas, &size).await {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    eprintln!("there was a disconnect on a worker, terminating it");
-                                    read_drain.abort();
-                                    return;
-                                },
-                            }
-                        }
-                    })
-                }
-                Mode::Write => {
-                    match_parser!(proto: protocol => {
-                        loop {
-                            let color = random();
-                            match proto.send_frame(&mut writer, canvas, color, &size).await {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    eprintln!("there was a disconnect on a worker, terminating it");
-                                    read_drain.abort();
-                                    return;
-                                },
-                            }
-                        }
-                    })
+        let worker = Worker {
+            thread,
+            job: job.clone(),
+            stats: stats.workers[thread].clone(),
+            video: video.clone(),
+            rng: StdRng::from_os_rng(),
+            backoff: backoff.clone(),
+            limits: limits.with_own(pixel_rate, context.args.byte_rate),
+            stop: stop_rx.clone(),
+            frames: 0,
+        };
+        workers.spawn(async move {
+            match_parser!(proto: protocol => {
+                worker.run(&mut proto).await;
+                return;
+            })
+        });
+    }
+    println!("Spawned threads");
+
+    let mut dashboard = context.args.tui.then(|| {
+        let stats = stats.clone();
+        let title = format!("tsunami {} {:?} {:?}", host, protocol, mode);
+        tokio::task::spawn_blocking(move || dashboard::run(&stats, &title))
+    });
+    let duration = context.args.duration.map(Duration::from_secs);
+    let mut quit = None;
+    let stop = tokio::select! {
+        _ = join_all(&mut workers) => Stop::Done,
+        _ = tokio::signal::ctrl_c() => Stop::Interrupted,
+        _ = async {
+            match duration {
+                Some(duration) => sleep(duration).await,
+                None => std::future::pending().await,
+            }
+        } => Stop::Duration,
+        res = async {
+            match &mut dashboard {
+                Some(dashboard) => dashboard.await,
+                None => std::future::pending().await,
+            }
+        } => {
+            let stop = match res {
+                Ok(Ok(dashboard::Closed::Interrupted)) => Stop::Interrupted,
+                _ => Stop::Quit,
+            };
+            quit = Some(res);
+            stop
+        }
+    };
+    if stop != Stop::Done {
+        // the dashboard may still be drawing when the duration is up
+        let quiet = context.args.tui && quit.is_none();
+        if !quiet {
+            match stop {
+                Stop::Interrupted => println!("\nGot Ctrl-C, stopping"),
+                Stop::Duration => println!("Reached the duration limit, stopping"),
+                _ => println!("Stopping"),
+            }
+        }
+        let _ = stop_tx.send(true);
+        let timeout =
+            Duration::from_millis(context.args.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT));
+        if !quiet {
+            println!(
+                "Waiting up to {} ms for the threads to finish their frame, press Ctrl-C to quit right away",
+                timeout.as_millis()
+            );
+        }
+        tokio::select! {
+            _ = join_all(&mut workers) => {}
+            _ = sleep(timeout) => {
+                if !quiet {
+                    println!("Threads did not stop in time, aborting them");
                 }
-                Mode::Spray => {
-                    match_parser!(proto: protocol => {
-                        let mut rng = rand::rngs::StdRng::from_os_rng();
-                        loop {
-                            match proto.spray_frame(&mut writer, canvas, &mut rng, &size).await {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    eprintln!("there was a disconnect on a worker, terminating it");
-                                    read_drain.abort();
-                                    return;
-                                },
-                            }
-                        }
-                    })
+            }
+            _ = tokio::signal::ctrl_c() => {
+                if !quiet {
+                    println!("\nAborting the threads");
                 }
             }
-        }));
+        }
+    }
+    stats.finish();
+    workers.shutdown().await;
+    let dashboard = match (quit, dashboard) {
+        (Some(res), _) => Some(res),
+        (None, Some(dashboard)) => Some(dashboard.await),
+        (None, None) => None,
+    };
+    if let Some(res) = dashboard {
+        res.map_err(|e| Error::Custom(e.to_string()))??;
     }
-    println!("Spawned threads");
 
-    for handle in handles {
-        match handle.await {
-            Ok(_) => println!("handle closed without errors"),
-            Err(err) => println!("handle closed with error {}", err),
+    let report = Report::new(&stats);
+    println!("{}", report);
+    if let Some(path) = &context.args.report {
+        report.save(path)?;
+        println!("Saved report to {}", path.display());
+    }
+    let states: Vec<_> = stats.workers.iter().map(|w| w.state()).collect();
+    let failed = states.iter().filter(|&&s| s == WorkerState::Failed).count();
+    let unfinished = states.iter().filter(|&&s| s != WorkerState::Done).count();
+    if matches!(mode, Mode::Snapshot | Mode::Restore) && unfinished > 0 {
+        let err = format!(
+            "{} of {} threads did not finish, the {} is incomplete",
+            unfinished,
+            threads,
+            if matches!(mode, Mode::Snapshot) {
+                "snapshot"
+            } else {
+                "restore"
+            }
+        );
+        if stop == Stop::Interrupted {
+            eprintln!("{}", err);
+            return Ok(ExitCode::from(EXIT_INTERRUPTED));
+        }
+        return Err(Error::Custom(err));
+    }
+    if let (Mode::Snapshot, Some(framebuffer), Some(path)) =
+        (mode, framebuffer.get(), &context.args.file)
+    {
+        let size = CanvasSize {
+            x: framebuffer.width,
+            y: framebuffer.height,
+        };
+        let region = Region::clipped(x_offset, y_offset, width, height, &size);
+        framebuffer
+            .to_frame()
+            .crop(region.x, region.y, region.width, region.height)
+            .save(path)?;
+        println!(
+            "Saved {}x{} snapshot to {}",
+            region.width,
+            region.height,
+            path.display()
+        );
+    }
+    let last_frame = job
+        .last_frame
+        .as_ref()
+        .and_then(|f| f.lock().unwrap().take());
+    if let (Some(frame), Some(path)) = (last_frame, &context.args.file) {
+        frame.save(path)?;
+        println!(
+            "Saved the last {}x{} frame to {}",
+            frame.width,
+            frame.height,
+            path.display()
+        );
+    }
+    if let Some(video_task) = video_task {
+        video_task.abort();
+        if let Ok(res) = video_task.await {
+            res?;
         }
     }
 
-    Ok(())
+    if stop == Stop::Interrupted {
+        return Ok(ExitCode::from(EXIT_INTERRUPTED));
+    }
+    if failed > 0 {
+        eprintln!("{} of {} threads gave up", failed, threads);
+        return Ok(ExitCode::from(EXIT_THREADS_FAILED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tsunami::server::Server;
+
+    const TEAL: Color = Color::RGB24(0x12, 0x34, 0x56);
+
+    /// Serve `server` on a free local port, returns its address.
+    async fn serve(server: Server) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { server.serve(listener).await });
+        addr
+    }
+
+    /// A plaintext job for `mode` on the whole canvas of the server at `addr`.
+    fn job(addr: &str, mode: Mode, frame_limit: Option<u64>) -> Job {
+        let protocol = Protocol::Plaintext;
+        Job {
+            endpoint: Endpoint::tcp(addr),
+            protocol,
+            framing: match mode {
+                Mode::Read | Mode::Snapshot => protocol.get_framing(),
+                _ => protocol.framing(),
+            },
+            mode,
+            canvas: 0,
+            x_offset: 0,
+            y_offset: 0,
+            width: None,
+            height: None,
+            threads: 1,
+            preamble_timeout: Duration::from_secs(1),
+            size: None,
+            encoding: Encoding::default(),
+            frame_limit,
+            cleanup: false,
+            debug: false,
+            quiet: true,
+            image: None,
+            framebuffer: Arc::new(OnceLock::new()),
+            last_frame: None,
+        }
+    }
+
+    /// The only worker of `job`, and the switch that tells it to stop.
+    fn worker(job: Arc<Job>) -> (Worker, watch::Sender<bool>) {
+        let (stop_tx, stop) = watch::channel(false);
+        let worker = Worker {
+            thread: 0,
+            job,
+            stats: Stats::new(1).workers[0].clone(),
+            video: None,
+            rng: StdRng::seed_from_u64(0),
+            backoff: Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+                Some(0),
+            ),
+            limits: Limits::default(),
+            stop,
+            frames: 0,
+        };
+        (worker, stop_tx)
+    }
+
+    /// What a worker sends for `frames` frames, with text fills of the whole canvas.
+    fn sent_bytes(size: &CanvasSize, frames: usize) -> u64 {
+        let mut buf = Vec::new();
+        encode_fill::<text::Protocol>(&mut buf, 0, TEAL, &Region::from(size), &Encoding::default())
+            .unwrap();
+        (b"CANVAS 0\nSIZE\n".len() + frames * buf.len()) as u64
+    }
+
+    #[tokio::test]
+    async fn test_frame_limit() {
+        let size = CanvasSize { x: 4, y: 3 };
+        let addr = serve(Server::new(Protocol::Plaintext, size)).await;
+
+        let (worker, _stop) = worker(Arc::new(job(&addr, Mode::Write, Some(3))));
+        let stats = worker.stats.clone();
+        worker.run(&mut text::Protocol { buf: Vec::new() }).await;
+
+        assert_eq!(stats.state(), WorkerState::Done);
+        assert_eq!(stats.counters().frames, 3);
+        assert_eq!(stats.counters().bytes, sent_bytes(&size, 3));
+    }
+
+    #[tokio::test]
+    async fn test_stop_finishes_frame_and_cleans_up() {
+        let size = CanvasSize { x: 100, y: 60 };
+        let server = Server::new(Protocol::Plaintext, size);
+        let canvas = server.canvas();
+        let addr = serve(server).await;
+
+        let job = Job {
+            cleanup: true,
+            ..job(&addr, Mode::Write, None)
+        };
+        let (mut worker, stop) = worker(Arc::new(job));
+        // a frame takes 300 ms at this rate
+        worker.limits = Limits::default().with_own(Some(20_000), None);
+        let stats = worker.stats.clone();
+        let run = tokio::spawn(async move {
+            worker.run(&mut text::Protocol { buf: Vec::new() }).await;
+        });
+        sleep(Duration::from_millis(100)).await;
+        stop.send(true).unwrap();
+        run.await.unwrap();
+
+        assert_eq!(stats.state(), WorkerState::Done);
+        assert_eq!(stats.counters().frames, 1);
+        // the whole frame and the whole cleanup went out
+        assert_eq!(stats.counters().bytes, sent_bytes(&size, 2));
+        for _ in 0..100 {
+            if canvas.get(size.x - 1, size.y - 1) == CLEANUP_COLOR {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        for (x, y) in (0..size.y).flat_map(|y| (0..size.x).map(move |x| (x, y))) {
+            assert_eq!(canvas.get(x, y), CLEANUP_COLOR);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_keeps_last_frame() {
+        let server = Server::new(Protocol::Plaintext, CanvasSize { x: 4, y: 3 });
+        let canvas = server.canvas();
+        canvas.set(1, 1, TEAL);
+        canvas.set(3, 2, Color::RGB24(0xff, 0, 0x80));
+        let addr = serve(server).await;
+
+        let job = Arc::new(Job {
+            last_frame: Some(Mutex::default()),
+            ..job(&addr, Mode::Read, Some(3))
+        });
+        let (worker, _stop) = worker(job.clone());
+        let stats = worker.stats.clone();
+        worker.run(&mut text::Protocol { buf: Vec::new() }).await;
+
+        assert_eq!(stats.state(), WorkerState::Done);
+        let last_frame = job.last_frame.as_ref().unwrap().lock().unwrap().take();
+        assert_eq!(last_frame, Some(canvas.to_frame()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("last.png");
+        last_frame.unwrap().save(&path).unwrap();
+        assert_eq!(Frame::load(&path).unwrap(), canvas.to_frame());
+    }
 }
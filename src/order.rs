@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// The order in which the pixels of a region get drawn or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PixelOrder {
+    /// Row by row, left to right
+    #[default]
+    Scanline,
+    /// Column by column, top to bottom
+    ColumnMajor,
+    /// Along a Hilbert curve, so nearby pixels are drawn close together in time
+    Hilbert,
+    /// Every 8th row first, then fill in the rows in between
+    Interlaced,
+    /// A seeded random permutation, every pixel once
+    Random,
+}
+
+/// Rows of each interlaced pass, as (first row, step) like PNG's Adam7.
+const INTERLACE_PASSES: [(u16, u16); 4] = [(0, 8), (4, 8), (2, 4), (1, 2)];
+
+impl PixelOrder {
+    /// Call `f` once for every pixel of a `width` by `height` area, in order.
+    /// `seed` picks the permutation of the random order.
+    pub fn visit(&self, width: u16, height: u16, seed: u64, mut f: impl FnMut(u16, u16)) {
+        match self {
+            PixelOrder::Scanline => {
+                for y in 0..height {
+                    for x in 0..width {
+                        f(x, y);
+                    }
+                }
+            }
+            PixelOrder::ColumnMajor => {
+                for x in 0..width {
+                    for y in 0..height {
+                        f(x, y);
+                    }
+                }
+            }
+            PixelOrder::Hilbert => visit_hilbert(width, height, f),
+            PixelOrder::Interlaced => {
+                for (first, step) in INTERLACE_PASSES {
+                    for y in (first..height).step_by(step as usize) {
+                        for x in 0..width {
+                            f(x, y);
+                        }
+                    }
+                }
+            }
+            PixelOrder::Random => {
+                for &i in cached_permutation(width, height, seed).iter() {
+                    f((i % width as u32) as u16, (i / width as u32) as u16);
+                }
+            }
+        }
+    }
+}
+
+/// Random orders that were shuffled already, by width, height and seed.
+type Permutations = HashMap<(u16, u16, u64), Arc<[u32]>>;
+
+static PERMUTATIONS: LazyLock<Mutex<Permutations>> = LazyLock::new(Default::default);
+
+/// How many random orders are kept before the cache starts over, workers
+/// only ever draw a handful of regions.
+const PERMUTATION_CACHE: usize = 64;
+
+/// The random order of a `width` by `height` area, which is the same for
+/// every frame, so it only gets shuffled the first time.
+fn cached_permutation(width: u16, height: u16, seed: u64) -> Arc<[u32]> {
+    let key = (width, height, seed);
+    let mut cache = PERMUTATIONS.lock().unwrap();
+    if cache.len() >= PERMUTATION_CACHE && !cache.contains_key(&key) {
+        cache.clear();
+    }
+    cache
+        .entry(key)
+        .or_insert_with(|| permutation(width, height, &mut StdRng::seed_from_u64(seed)).into())
+        .clone()
+}
+
+/// Every pixel index of a `width` by `height` area, shuffled.
+pub fn permutation<R: Rng>(width: u16, height: u16, rng: &mut R) -> Vec<u32> {
+    let mut indices: Vec<u32> = (0..width as u32 * height as u32).collect();
+    indices.shuffle(rng);
+    indices
+}
+
+/// Call `f` for every pixel of a `width` by `height` area, along the Hilbert
+/// curve of the smallest square around it.
+fn visit_hilbert(width: u16, height: u16, mut f: impl FnMut(u16, u16)) {
+    let (width, height) = (width as u64, height as u64);
+    // u16 has no room for the next power of two above 32768
+    let side = width.max(height).max(1).next_power_of_two();
+    let mut remaining = width * height;
+    let mut d = 0;
+    while remaining > 0 {
+        let (x, y) = hilbert(side, d);
+        if x < width && y < height {
+            f(x as u16, y as u16);
+            remaining -= 1;
+            d += 1;
+            continue;
+        }
+        // 4^k steps from a multiple of 4^k fill an aligned square of 2^k,
+        // skip the largest one that starts here and lies outside the area
+        let mut s = 1;
+        while s < side && d % (4 * s * s) == 0 && {
+            let mask = !(2 * s - 1);
+            x & mask >= width || y & mask >= height
+        } {
+            s *= 2;
+        }
+        d += s * s;
+    }
+}
+
+/// Point `d` along the Hilbert curve that fills a `side` by `side` square.
+fn hilbert(side: u64, d: u64) -> (u64, u64) {
+    let (mut x, mut y, mut t) = (0, 0, d);
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    fn collect(order: PixelOrder, width: u16, height: u16, seed: u64) -> Vec<(u16, u16)> {
+        let mut points = Vec::new();
+        order.visit(width, height, seed, |x, y| points.push((x, y)));
+        points
+    }
+
+    #[test_case(PixelOrder::Scanline ; "scanline")]
+    #[test_case(PixelOrder::ColumnMajor ; "column major")]
+    #[test_case(PixelOrder::Hilbert ; "hilbert")]
+    #[test_case(PixelOrder::Interlaced ; "interlaced")]
+    #[test_case(PixelOrder::Random ; "random")]
+    fn test_covers_every_pixel_once(order: PixelOrder) {
+        let mut points = collect(order, 7, 11, 42);
+        points.sort();
+        let expected: Vec<_> = (0..7).flat_map(|x| (0..11).map(move |y| (x, y))).collect();
+        assert_eq!(points, expected);
+    }
+
+    #[test_case(40_000, 1 ; "wide")]
+    #[test_case(3, 40_000 ; "tall")]
+    fn test_hilbert_large(width: u16, height: u16) {
+        let mut visits = 0;
+        PixelOrder::Hilbert.visit(width, height, 0, |x, y| {
+            assert!(x < width && y < height);
+            visits += 1;
+        });
+        assert_eq!(visits, width as u64 * height as u64);
+    }
+
+    #[test]
+    fn test_random_cached() {
+        let first = cached_permutation(9, 4, 3);
+        assert!(Arc::ptr_eq(&first, &cached_permutation(9, 4, 3)));
+        assert!(!Arc::ptr_eq(&first, &cached_permutation(9, 4, 4)));
+    }
+
+    #[test]
+    fn test_orders() {
+        assert_eq!(
+            collect(PixelOrder::ColumnMajor, 2, 2, 0),
+            [(0, 0), (0, 1), (1, 0), (1, 1)]
+        );
+        assert_eq!(
+            collect(PixelOrder::Hilbert, 2, 2, 0),
+            [(0, 0), (0, 1), (1, 1), (1, 0)]
+        );
+        let rows: Vec<_> = collect(PixelOrder::Interlaced, 1, 9, 0)
+            .into_iter()
+            .map(|(_, y)| y)
+            .collect();
+        assert_eq!(rows, [0, 8, 4, 2, 6, 1, 3, 5, 7]);
+        assert_eq!(
+            collect(PixelOrder::Random, 5, 5, 7),
+            collect(PixelOrder::Random, 5, 5, 7)
+        );
+    }
+}
@@ -1,24 +1,94 @@
+use std::{future::Future, sync::OnceLock, time::Duration};
+
 use atoi_radix10::parse_from_str;
 use clap::ValueEnum;
 use rand::Rng;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    runtime::{Handle, RuntimeFlavor},
+    task::block_in_place,
+};
 
-use crate::{Color, Result};
+use crate::{permutation, Color, Endpoint, Error, Frame, Framebuffer, PixelOrder, Result};
 
 pub mod binary;
+pub mod breakwater;
 pub mod flutties;
 pub mod palette;
 pub mod text;
 
 macro_rules! build_protocol_mode_enum {
-    ($($name:ident: $t:expr,)*) => {
+    ($($name:ident: $p:ty => $t:expr,)*) => {
 
-        #[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum, Hash, Default)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum, Hash, Default)]
         #[serde(rename_all = "kebab-case")]
         pub enum Protocol {
             #[default]
             $($name,)*
+            /// Probe the server for the fastest protocol it speaks
+            Auto,
+        }
+
+        impl Protocol {
+            /// Whether transparent colours can be drawn.
+            pub fn supports_alpha(&self) -> bool {
+                match self {
+                    $(Protocol::$name => <$p as Proto>::ALPHA,)*
+                    Protocol::Auto => unreachable!("auto is detected before drawing"),
+                }
+            }
+
+            /// Most pixels per second one connection can send, if the server needs a cap.
+            pub fn max_pixel_rate(&self) -> Option<u64> {
+                match self {
+                    $(Protocol::$name => <$p as Proto>::MAX_PIXEL_RATE,)*
+                    Protocol::Auto => unreachable!("auto is detected before connecting"),
+                }
+            }
+
+            /// Where one command ends and the next one starts.
+            pub fn framing(&self) -> Framing {
+                match self {
+                    $(Protocol::$name => <$p as Proto>::FRAMING,)*
+                    Protocol::Auto => unreachable!("auto is detected before connecting"),
+                }
+            }
+
+            /// Where one get command ends and the next one starts.
+            pub fn get_framing(&self) -> Framing {
+                match self {
+                    $(Protocol::$name => <$p as Proto>::GET_FRAMING,)*
+                    Protocol::Auto => unreachable!("auto is detected before connecting"),
+                }
+            }
+
+            /// Encode the commands that draw `image` into `region`, split in
+            /// `count` bands of rows that get encoded in parallel.
+            pub fn encode_image_bands(
+                &self,
+                canvas: u8,
+                image: &Frame,
+                region: &Region,
+                encoding: &Encoding,
+                count: usize,
+            ) -> Result<Vec<Vec<u8>>> {
+                (0..count)
+                    .into_par_iter()
+                    .map(|index| {
+                        let band = region.band(index, count);
+                        let mut buf = Vec::new();
+                        match self {
+                            $(Protocol::$name => {
+                                encode_image_band::<$p>(&mut buf, canvas, image, region, &band, encoding)?
+                            })*
+                            Protocol::Auto => unreachable!("auto is detected before drawing"),
+                        }
+                        Ok(buf)
+                    })
+                    .collect()
+            }
         }
 
         #[macro_export]
@@ -33,6 +103,7 @@ macro_rules! build_protocol_mode_enum {
                             }
                         },
                     )*
+                    Protocol::Auto => unreachable!("auto is detected before connecting"),
                 }
             )
         }
@@ -40,47 +111,482 @@ macro_rules! build_protocol_mode_enum {
 }
 
 build_protocol_mode_enum! {
-    Plaintext: text::Protocol{str: String::with_capacity(18), count: 0},
-    BinFlurry: binary::Protocol{count: 0},
-    BinFlutties: flutties::Protocol{count: 0},
-    Palette: palette::Protocol{count: 0},
+    Plaintext: text::Protocol => text::Protocol{buf: Vec::new()},
+    BinFlurry: binary::Protocol => binary::Protocol{buf: Vec::new()},
+    BinFlutties: flutties::Protocol => flutties::Protocol{buf: Vec::new()},
+    Palette: palette::Protocol => palette::Protocol{buf: Vec::new()},
+    BinBreakwater: breakwater::Protocol => breakwater::Protocol{buf: Vec::new()},
+}
+
+/// How frames get encoded, independent of the protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Encoding {
+    /// Draw in tiles, each with an `OFFSET` and short local coordinates
+    pub offset: bool,
+    /// Send opaque grays in the short form, for servers that accept it
+    pub gray: bool,
+    /// The order to visit pixels in, tiles are visited in the same order
+    pub order: PixelOrder,
+    /// Picks the permutation of the random order
+    pub seed: u64,
+}
+
+/// How commands are delimited, so they can be packed without splitting one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Every command ends with a newline
+    Lines,
+    /// Every command is this many bytes long
+    Fixed(usize),
+}
+
+impl Framing {
+    /// The length of the longest prefix of `bytes` that only holds whole commands.
+    pub fn whole(&self, bytes: &[u8]) -> usize {
+        match self {
+            Framing::Lines => bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1),
+            Framing::Fixed(len) => bytes.len() / len * len,
+        }
+    }
 }
 
-pub trait Proto {
+/// Tiles of 100x100 keep local coordinates at two digits.
+const OFFSET_TILE: u16 = 100;
+
+pub trait Proto: Sized {
+    const PROTOCOL: Protocol;
+
+    /// How set commands are delimited on the wire.
+    const FRAMING: Framing;
+
+    /// How get commands are delimited on the wire.
+    const GET_FRAMING: Framing = Self::FRAMING;
+
+    /// Whether transparent colours can be drawn.
+    const ALPHA: bool = false;
+
+    /// Whether the origin of later commands can be moved with `encode_offset`.
+    const OFFSET: bool = false;
+
+    /// Whether opaque grays have a shorter command in `encode_gray`.
+    const GRAY: bool = false;
+
+    /// Most pixels per second one connection can send, for servers that can not keep up.
+    const MAX_PIXEL_RATE: Option<u64> = None;
+
+    /// Append the command that sets a single pixel.
+    fn encode_set(buf: &mut Vec<u8>, canvas: u8, x: u16, y: u16, color: Color);
+
+    /// Append the command that sets a single pixel to an opaque gray.
+    fn encode_gray(buf: &mut Vec<u8>, canvas: u8, x: u16, y: u16, w: u8) {
+        Self::encode_set(buf, canvas, x, y, Color::Gray8(w))
+    }
+
+    /// Append the command that requests a single pixel.
+    fn encode_get(buf: &mut Vec<u8>, canvas: u8, x: u16, y: u16);
+
+    /// Append the command that moves the origin of later commands.
+    fn encode_offset(_buf: &mut Vec<u8>, _x: u16, _y: u16) {}
+
+    /// The buffer that frames get encoded into, kept from frame to frame.
+    fn buf(&mut self) -> &mut Vec<u8>;
+
+    /// Send a frame of set commands that was encoded up front.
+    #[allow(async_fn_in_trait)]
+    async fn send_encoded<W: AsyncWriteExt + std::marker::Unpin>(
+        &mut self,
+        writer: &mut W,
+        frame: &[u8],
+    ) -> Result<()> {
+        writer.write_all(frame).await?;
+        Ok(())
+    }
+
+    /// Fill `region` with `color`.
     #[allow(async_fn_in_trait)]
     async fn send_frame<W: AsyncWriteExt + std::marker::Unpin>(
         &mut self,
         writer: &mut W,
         canvas: u8,
         color: Color,
-        size: &CanvasSize,
-    ) -> Result<()>;
+        region: &Region,
+        encoding: &Encoding,
+    ) -> Result<()> {
+        let buf = self.buf();
+        buf.clear();
+        encode_fill::<Self>(buf, canvas, color, region, encoding)?;
+        writer.write_all(buf).await?;
+        Ok(())
+    }
 
+    /// Draw a region worth of pixels of one random colour at random places.
     #[allow(async_fn_in_trait)]
     async fn spray_frame<W: AsyncWriteExt + std::marker::Unpin, R: Rng>(
         &mut self,
         writer: &mut W,
         canvas: u8,
         rng: &mut R,
-        size: &CanvasSize,
-    ) -> Result<()>;
+        region: &Region,
+        encoding: &Encoding,
+    ) -> Result<()> {
+        let buf = self.buf();
+        buf.clear();
+        encode_spray::<Self, R>(buf, canvas, rng, region, encoding);
+        writer.write_all(buf).await?;
+        Ok(())
+    }
 
+    /// Request every pixel of `region`, `read_frame` reads the responses.
     #[allow(async_fn_in_trait)]
     async fn get_frame<W: AsyncWriteExt + std::marker::Unpin>(
         &mut self,
         writer: &mut W,
         canvas: u8,
-        size: &CanvasSize,
+        region: &Region,
+        encoding: &Encoding,
+    ) -> Result<()> {
+        let buf = self.buf();
+        buf.clear();
+        encode_get_frame::<Self>(buf, canvas, region, encoding);
+        writer.write_all(buf).await?;
+        Ok(())
+    }
+
+    /// Read the responses to one `get_frame` call into `framebuffer`.
+    #[allow(async_fn_in_trait)]
+    async fn read_frame<R: AsyncBufReadExt + std::marker::Unpin>(
+        &mut self,
+        reader: &mut R,
+        canvas: u8,
+        region: &Region,
+        encoding: &Encoding,
+        framebuffer: &Framebuffer,
     ) -> Result<()>;
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Visit every pixel of `region` with its canvas coordinates and the
+/// coordinates to put on the wire, tile by tile when offsets are used.
+fn encode_pixels<P: Proto>(
+    buf: &mut Vec<u8>,
+    region: &Region,
+    encoding: &Encoding,
+    mut pixel: impl FnMut(&mut Vec<u8>, (u16, u16), (u16, u16)),
+) {
+    let (order, seed) = (encoding.order, encoding.seed);
+    if encoding.offset && P::OFFSET {
+        let tiles: Vec<Region> = region.tiles(OFFSET_TILE).collect();
+        let columns = region.width.div_ceil(OFFSET_TILE);
+        let rows = region.height.div_ceil(OFFSET_TILE);
+        order.visit(columns, rows, seed, |column, row| {
+            let tile = tiles[row as usize * columns as usize + column as usize];
+            P::encode_offset(buf, tile.x, tile.y);
+            order.visit(tile.width, tile.height, seed, |i, j| {
+                pixel(buf, (tile.x + i, tile.y + j), (i, j))
+            });
+        });
+    } else {
+        order.visit(region.width, region.height, seed, |i, j| {
+            let (x, y) = (region.x + i, region.y + j);
+            pixel(buf, (x, y), (x, y))
+        });
+    }
+}
+
+/// Encode filling all of `region` with `color`.
+pub fn encode_fill<P: Proto>(
+    buf: &mut Vec<u8>,
+    canvas: u8,
+    color: Color,
+    region: &Region,
+    encoding: &Encoding,
+) -> Result<()> {
+    check_alpha::<P>(color)?;
+    encode_pixels::<P>(buf, region, encoding, |buf, _, (x, y)| {
+        encode_set::<P>(buf, canvas, x, y, color, encoding)
+    });
+    Ok(())
+}
+
+/// Encode drawing `image` at the top left of `region`, clipped to it.
+pub fn encode_image<P: Proto>(
+    buf: &mut Vec<u8>,
+    canvas: u8,
+    image: &Frame,
+    region: &Region,
+    encoding: &Encoding,
+) -> Result<()> {
+    encode_image_band::<P>(buf, canvas, image, region, region, encoding)
+}
+
+/// Encode only the rows in `band` of drawing `image` into `region`.
+pub fn encode_image_band<P: Proto>(
+    buf: &mut Vec<u8>,
+    canvas: u8,
+    image: &Frame,
+    region: &Region,
+    band: &Region,
+    encoding: &Encoding,
+) -> Result<()> {
+    let (w, h) = region.visible(image);
+    let first = band.y - region.y;
+    let visible = Region {
+        x: region.x,
+        y: band.y,
+        width: w,
+        height: (first + band.height).min(h).saturating_sub(first),
+    };
+    for j in first..first + visible.height {
+        for i in 0..w {
+            check_alpha::<P>(image.get(i, j))?;
+        }
+    }
+    encode_pixels::<P>(buf, &visible, encoding, |buf, (i, j), (x, y)| {
+        let color = image.get(i - region.x, j - region.y);
+        encode_set::<P>(buf, canvas, x, y, color, encoding)
+    });
+    Ok(())
+}
+
+/// Encode setting a single pixel, in the short form for grays if `encoding` allows it.
+fn encode_set<P: Proto>(
+    buf: &mut Vec<u8>,
+    canvas: u8,
+    x: u16,
+    y: u16,
+    color: Color,
+    encoding: &Encoding,
+) {
+    match color.rgba() {
+        (r, g, b, u8::MAX) if encoding.gray && P::GRAY && r == g && g == b => {
+            P::encode_gray(buf, canvas, x, y, r)
+        }
+        _ => P::encode_set(buf, canvas, x, y, color),
+    }
+}
+
+fn check_alpha<P: Proto>(color: Color) -> Result<()> {
+    match P::ALPHA || color.is_opaque() {
+        true => Ok(()),
+        false => Err(Error::UnsupportedAlpha(P::PROTOCOL)),
+    }
+}
+
+/// Encode a region worth of pixels at random places in `region`, all with the same colour.
+///
+/// The random order draws a fresh permutation, to hit every pixel once per frame.
+pub fn encode_spray<P: Proto, R: Rng>(
+    buf: &mut Vec<u8>,
+    canvas: u8,
+    rng: &mut R,
+    region: &Region,
+    encoding: &Encoding,
+) {
+    let color = rng.random();
+    let (ox, oy) = match encoding.offset && P::OFFSET {
+        true => {
+            P::encode_offset(buf, region.x, region.y);
+            (region.x, region.y)
+        }
+        false => (0, 0),
+    };
+    if encoding.order == PixelOrder::Random {
+        for i in permutation(region.width, region.height, rng) {
+            let x = region.x + (i % region.width as u32) as u16;
+            let y = region.y + (i / region.width as u32) as u16;
+            encode_set::<P>(buf, canvas, x - ox, y - oy, color, encoding);
+        }
+        return;
+    }
+    for _ in 0..region.pixels() {
+        let x = rng.random_range(region.x..region.x + region.width);
+        let y = rng.random_range(region.y..region.y + region.height);
+        encode_set::<P>(buf, canvas, x - ox, y - oy, color, encoding);
+    }
+}
+
+/// Encode requesting every pixel in `region`.
+///
+/// Coordinates are always absolute, so responses can be matched to pixels.
+pub fn encode_get_frame<P: Proto>(
+    buf: &mut Vec<u8>,
+    canvas: u8,
+    region: &Region,
+    encoding: &Encoding,
+) {
+    if encoding.offset && P::OFFSET {
+        P::encode_offset(buf, 0, 0);
+    }
+    encoding
+        .order
+        .visit(region.width, region.height, encoding.seed, |i, j| {
+            P::encode_get(buf, canvas, region.x + i, region.y + j)
+        });
+}
+
+/// A frame that many workers draw into the same region, so it is encoded only once.
+pub struct SharedFrame {
+    pub frame: Frame,
+    alpha: bool,
+    bands: OnceLock<Vec<Vec<u8>>>,
+}
+
+impl SharedFrame {
+    pub fn new(frame: Frame) -> Self {
+        Self {
+            alpha: frame.has_alpha(),
+            frame,
+            bands: OnceLock::new(),
+        }
+    }
+
+    /// Band `index` of the frame encoded in `count` bands, the first caller
+    /// decides how it gets drawn.
+    pub fn encoded(
+        &self,
+        protocol: Protocol,
+        canvas: u8,
+        region: &Region,
+        encoding: &Encoding,
+        index: usize,
+        count: usize,
+    ) -> Result<&[u8]> {
+        if self.alpha && !protocol.supports_alpha() {
+            return Err(Error::UnsupportedAlpha(protocol));
+        }
+        if let Some(bands) = self.bands.get() {
+            return Ok(&bands[index]);
+        }
+        let encode = || {
+            self.bands.get_or_init(|| {
+                protocol
+                    .encode_image_bands(canvas, &self.frame, region, encoding, count)
+                    .expect("alpha was checked up front")
+            })
+        };
+        // encoding a frame keeps this thread busy for a while, and so does waiting
+        // for another worker to encode it, hand the other tasks to another thread
+        let bands = match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+            Ok(RuntimeFlavor::MultiThread) => block_in_place(encode),
+            _ => encode(),
+        };
+        Ok(&bands[index])
+    }
+}
+
+/// Read raw RGB responses, which arrive in the order `get_frame` requested them.
+async fn read_rgb_frame<R: AsyncReadExt + std::marker::Unpin>(
+    reader: &mut R,
+    region: &Region,
+    encoding: &Encoding,
+    framebuffer: &Framebuffer,
+) -> Result<()> {
+    let mut pixels = vec![0; region.pixels() as usize * 3];
+    reader.read_exact(&mut pixels).await?;
+    let mut px = pixels.chunks_exact(3);
+    encoding
+        .order
+        .visit(region.width, region.height, encoding.seed, |i, j| {
+            if let Some(px) = px.next() {
+                framebuffer.set(
+                    region.x + i,
+                    region.y + j,
+                    Color::RGB24(px[0], px[1], px[2]),
+                );
+            }
+        });
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct CanvasSize {
     pub x: u16,
     pub y: u16,
 }
 
+/// The part of the canvas that gets drawn or read.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Region {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Region {
+    /// Place an area at (`x`, `y`) and clip it to the canvas, missing
+    /// dimensions extend up to the canvas edge.
+    pub fn clipped(
+        x: u16,
+        y: u16,
+        width: Option<u16>,
+        height: Option<u16>,
+        size: &CanvasSize,
+    ) -> Self {
+        let max_width = size.x.saturating_sub(x);
+        let max_height = size.y.saturating_sub(y);
+        Self {
+            x: x.min(size.x),
+            y: y.min(size.y),
+            width: width.map_or(max_width, |w| w.min(max_width)),
+            height: height.map_or(max_height, |h| h.min(max_height)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Split the region into tiles of at most `size` by `size`, row by row.
+    pub fn tiles(&self, size: u16) -> impl Iterator<Item = Region> + '_ {
+        (self.y..self.y + self.height)
+            .step_by(size as usize)
+            .flat_map(move |y| {
+                (self.x..self.x + self.width)
+                    .step_by(size as usize)
+                    .map(move |x| Region {
+                        x,
+                        y,
+                        width: size.min(self.x + self.width - x),
+                        height: size.min(self.y + self.height - y),
+                    })
+            })
+    }
+
+    /// Split the region into `count` bands of rows and take band `index`.
+    pub fn band(&self, index: usize, count: usize) -> Self {
+        let start = self.height as usize * index / count;
+        let end = self.height as usize * (index + 1) / count;
+        Self {
+            x: self.x,
+            y: self.y + start as u16,
+            width: self.width,
+            height: (end - start) as u16,
+        }
+    }
+
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    /// The part of `image` that fits in this region, in image coordinates.
+    pub fn visible(&self, image: &Frame) -> (u16, u16) {
+        (image.width.min(self.width), image.height.min(self.height))
+    }
+}
+
+impl From<&CanvasSize> for Region {
+    fn from(size: &CanvasSize) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: size.x,
+            height: size.y,
+        }
+    }
+}
+
 impl Protocol {
+    /// Ask the server for the canvas size, each step gives up after `timeout`.
     pub async fn preamble<
         W: AsyncWriteExt + std::marker::Unpin,
         R: AsyncBufReadExt + std::marker::Unpin,
@@ -89,52 +595,344 @@ impl Protocol {
         writer: &mut W,
         reader: &mut R,
         canvas: u8,
+        timeout: Duration,
     ) -> Result<CanvasSize> {
         match self {
             Protocol::Plaintext => {
-                writer
-                    .write_all(format!("CANVAS {}\nSIZE\n", canvas).as_bytes())
-                    .await?;
-                writer.flush().await?;
-                let mut line = "".to_string();
-                reader.read_line(&mut line).await?;
-
-                let mut split = line.trim().split(" ");
-                let _command = split.next();
-                let x = split.next().unwrap();
-                let y = split.next().unwrap();
-
-                let x = parse_from_str(x).unwrap();
-                let y = parse_from_str(y).unwrap();
-
-                Ok(CanvasSize { x, y })
+                let command = "SIZE";
+                step(command, timeout, async {
+                    writer
+                        .write_all(format!("CANVAS {}\nSIZE\n", canvas).as_bytes())
+                        .await?;
+                    writer.flush().await
+                })
+                .await?;
+                let mut line = String::new();
+                step(command, timeout, reader.read_line(&mut line)).await?;
+                parse_size(command, &line)
             }
             Protocol::BinFlurry => {
                 const SIZE_BIN: u8 = 115;
-                writer.write_all(b"PROTOCOL binary\n").await?;
-                writer.write_all(&[SIZE_BIN, canvas]).await?;
-                writer.flush().await?;
-                let x = reader.read_u16().await?;
-                let y = reader.read_u16().await?;
-                Ok(CanvasSize { x, y })
+                let command = "PROTOCOL binary";
+                step(command, timeout, async {
+                    writer.write_all(b"PROTOCOL binary\n").await?;
+                    writer.write_all(&[SIZE_BIN, canvas]).await?;
+                    writer.flush().await
+                })
+                .await?;
+                read_binary_size(reader, command, timeout).await
             }
             Protocol::BinFlutties => {
                 const SIZE_BIN: u8 = 32;
-                writer.write_all(&[SIZE_BIN, canvas]).await?;
-                writer.flush().await?;
-                let x = reader.read_u16().await?;
-                let y = reader.read_u16().await?;
-                Ok(CanvasSize { x, y })
+                let command = "SIZE";
+                step(command, timeout, async {
+                    writer.write_all(&[SIZE_BIN, canvas]).await?;
+                    writer.flush().await
+                })
+                .await?;
+                read_binary_size(reader, command, timeout).await
             }
             Protocol::Palette => {
                 const SIZE_BIN: u8 = 115;
-                writer.write_all(b"PROTOCOL palette\n").await?;
-                writer.write_all(&[SIZE_BIN, canvas]).await?;
-                writer.flush().await?;
-                let x = reader.read_u16().await?;
-                let y = reader.read_u16().await?;
-                Ok(CanvasSize { x, y })
+                let command = "PROTOCOL palette";
+                step(command, timeout, async {
+                    writer.write_all(b"PROTOCOL palette\n").await?;
+                    writer.write_all(&[SIZE_BIN, canvas]).await?;
+                    writer.flush().await
+                })
+                .await?;
+                read_binary_size(reader, command, timeout).await
+            }
+            Protocol::BinBreakwater => {
+                // breakwater mixes text and binary commands on one connection
+                let command = "SIZE";
+                step(command, timeout, async {
+                    writer.write_all(b"SIZE\n").await?;
+                    writer.flush().await
+                })
+                .await?;
+                let mut line = String::new();
+                step(command, timeout, reader.read_line(&mut line)).await?;
+                parse_size(command, &line)
+            }
+            Protocol::Auto => unreachable!("auto is detected before connecting"),
+        }
+    }
+
+    /// Find the fastest protocol that the server at `endpoint` speaks.
+    ///
+    /// A text server may speak flurry binary too, flutties servers only speak
+    /// binary. Palette is never picked, as it can not draw every colour.
+    /// Neither is breakwater binary: `PB` gets no reply, so probing it would
+    /// mean drawing a pixel. Breakwater servers speak text too and get
+    /// detected as plaintext, pick `bin-breakwater` with `--protocol`.
+    pub async fn detect(endpoint: &Endpoint, canvas: u8, timeout: Duration) -> Result<Protocol> {
+        if let Err(text_err) = Protocol::Plaintext.probe(endpoint, canvas, timeout).await {
+            return match Protocol::BinFlutties.probe(endpoint, canvas, timeout).await {
+                Ok(_) => Ok(Protocol::BinFlutties),
+                Err(err) => Err(Error::Custom(format!(
+                    "Could not detect the protocol of {}, text: {}, flutties: {}",
+                    endpoint.host, text_err, err
+                ))),
+            };
+        }
+        match Protocol::BinFlurry.probe(endpoint, canvas, timeout).await {
+            Ok(_) => Ok(Protocol::BinFlurry),
+            Err(_) => Ok(Protocol::Plaintext),
+        }
+    }
+
+    /// Check whether the server at `endpoint` knows `OFFSET`, which only text
+    /// servers can. Servers answer commands they do not know with `ERROR`.
+    pub async fn supports_offset(&self, endpoint: &Endpoint, timeout: Duration) -> Result<bool> {
+        if !matches!(self, Protocol::Plaintext) {
+            return Ok(false);
+        }
+        let command = "OFFSET 0 0";
+        let (reader, mut writer) =
+            step("connect", timeout, endpoint.connect(self.framing())).await?;
+        let mut reader = BufReader::new(reader);
+        step(command, timeout, async {
+            writer.write_all(b"OFFSET 0 0\nSIZE\n").await?;
+            writer.flush().await
+        })
+        .await?;
+        let mut line = String::new();
+        step(command, timeout, reader.read_line(&mut line)).await?;
+        match parse_size(command, &line) {
+            Ok(_) => Ok(true),
+            Err(Error::UnsupportedCommand { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Run the preamble on a connection of its own.
+    pub async fn probe(
+        &self,
+        endpoint: &Endpoint,
+        canvas: u8,
+        timeout: Duration,
+    ) -> Result<CanvasSize> {
+        let (reader, mut writer) =
+            step("connect", timeout, endpoint.connect(self.framing())).await?;
+        let mut reader = BufReader::new(reader);
+        self.preamble(&mut writer, &mut reader, canvas, timeout)
+            .await
+    }
+}
+
+/// Run one step of the preamble, giving up after `timeout`.
+async fn step<T, E: Into<Error>>(
+    command: &str,
+    timeout: Duration,
+    step: impl Future<Output = std::result::Result<T, E>>,
+) -> Result<T> {
+    match tokio::time::timeout(timeout, step).await {
+        Ok(res) => res.map_err(Into::into),
+        Err(_) => Err(Error::Timeout {
+            command: command.to_string(),
+            after: timeout,
+        }),
+    }
+}
+
+/// Parse a `SIZE w h` reply, servers answer commands they do not know with `ERROR`.
+fn parse_size(command: &str, line: &str) -> Result<CanvasSize> {
+    let mut split = line.split_ascii_whitespace();
+    match (split.next(), split.next(), split.next()) {
+        (Some("SIZE"), Some(x), Some(y)) => {
+            if let (Ok(x), Ok(y)) = (parse_from_str(x), parse_from_str(y)) {
+                return Ok(CanvasSize { x, y });
             }
         }
+        (Some("ERROR"), ..) => {
+            return Err(Error::UnsupportedCommand {
+                command: command.to_string(),
+                reply: line.trim_end().to_string(),
+            })
+        }
+        _ => {}
+    }
+    Err(Error::UnexpectedResponse {
+        command: command.to_string(),
+        reply: line.trim_end().to_string(),
+    })
+}
+
+/// Read the big endian size that binary protocols reply with.
+///
+/// A text server that does not know the binary protocol answers with an
+/// `ERROR` line instead.
+async fn read_binary_size<R: AsyncBufReadExt + std::marker::Unpin>(
+    reader: &mut R,
+    command: &str,
+    timeout: Duration,
+) -> Result<CanvasSize> {
+    let mut reply = [0; 4];
+    match step(command, timeout, reader.read_exact(&mut reply)).await {
+        Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err(Error::UnexpectedResponse {
+                command: command.to_string(),
+                reply: String::new(),
+            });
+        }
+        res => res?,
+    };
+    if &reply == b"ERRO" {
+        let mut line = String::from("ERRO");
+        // the reply is attached on a best effort basis
+        let _ = step(command, timeout, reader.read_line(&mut line)).await;
+        return Err(Error::UnsupportedCommand {
+            command: command.to_string(),
+            reply: line.trim_end().to_string(),
+        });
+    }
+    Ok(CanvasSize {
+        x: u16::from_be_bytes([reply[0], reply[1]]),
+        y: u16::from_be_bytes([reply[2], reply[3]]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_image_bands() {
+        let size = CanvasSize { x: 4, y: 5 };
+        let region = Region::clipped(1, 1, None, None, &size);
+        let pixels: Vec<u8> = (0..3 * 3 * 4).collect();
+        let image = Frame::from_rgb(3, 4, &pixels).unwrap();
+
+        let mut whole = Vec::new();
+        encode_image::<text::Protocol>(&mut whole, 0, &image, &region, &Encoding::default())
+            .unwrap();
+        let bands = Protocol::Plaintext
+            .encode_image_bands(0, &image, &region, &Encoding::default(), 3)
+            .unwrap();
+
+        assert_eq!(bands.len(), 3);
+        assert_eq!(bands.concat(), whole);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_shared_frame_encoded_on_workers() {
+        let region = Region::from(&CanvasSize { x: 3, y: 4 });
+        let pixels: Vec<u8> = (0..3 * 3 * 4).collect();
+        let frame = Arc::new(SharedFrame::new(Frame::from_rgb(3, 4, &pixels).unwrap()));
+        let whole = Protocol::Plaintext
+            .encode_image_bands(0, &frame.frame, &region, &Encoding::default(), 1)
+            .unwrap();
+
+        let workers: Vec<_> = (0..4)
+            .map(|index| {
+                let frame = frame.clone();
+                tokio::spawn(async move {
+                    let encoding = Encoding::default();
+                    let encoded =
+                        frame.encoded(Protocol::Plaintext, 0, &region, &encoding, index, 4);
+                    encoded.unwrap().to_vec()
+                })
+            })
+            .collect();
+        let mut bands = Vec::new();
+        for worker in workers {
+            bands.push(worker.await.unwrap());
+        }
+
+        assert_eq!(bands.concat(), whole[0]);
+    }
+
+    #[test]
+    fn test_alpha_rejected() {
+        let region = Region::from(&CanvasSize { x: 2, y: 2 });
+        let color = Color::RGBA32(1, 2, 3, 4);
+        let mut buf = Vec::new();
+
+        let res =
+            encode_fill::<binary::Protocol>(&mut buf, 0, color, &region, &Encoding::default());
+        assert!(matches!(
+            res,
+            Err(Error::UnsupportedAlpha(Protocol::BinFlurry))
+        ));
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "BinFlurry can not draw transparent colours, use plaintext or bin-breakwater instead"
+        );
+        assert!(
+            encode_fill::<text::Protocol>(&mut buf, 0, color, &region, &Encoding::default())
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preamble_canvas() {
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"CANVAS 3\nSIZE\n")
+            .build();
+        let mut reader =
+            tokio::io::BufReader::new(tokio_test::io::Builder::new().read(b"SIZE 6 4\n").build());
+
+        let size = Protocol::Plaintext
+            .preamble(&mut writer, &mut reader, 3, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(size, CanvasSize { x: 6, y: 4 });
+    }
+
+    #[tokio::test]
+    async fn test_preamble_error_reply() {
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"CANVAS 0\nSIZE\n")
+            .build();
+        let mut reader = tokio::io::BufReader::new(
+            tokio_test::io::Builder::new()
+                .read(b"ERROR unknown command\n")
+                .build(),
+        );
+
+        let res = Protocol::Plaintext
+            .preamble(&mut writer, &mut reader, 0, Duration::from_secs(1))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::UnsupportedCommand { command, reply })
+                if command == "SIZE" && reply == "ERROR unknown command"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_preamble_garbage_reply() {
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"CANVAS 0\nSIZE\n")
+            .build();
+        let mut reader =
+            tokio::io::BufReader::new(tokio_test::io::Builder::new().read(b"SIZE 800\n").build());
+
+        let res = Protocol::Plaintext
+            .preamble(&mut writer, &mut reader, 0, Duration::from_secs(1))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedResponse { reply, .. }) if reply == "SIZE 800"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_preamble_timeout() {
+        let (_server, client) = tokio::io::duplex(64);
+        let mut reader = tokio::io::BufReader::new(client);
+
+        let res = Protocol::BinFlutties
+            .preamble(
+                &mut tokio::io::sink(),
+                &mut reader,
+                0,
+                Duration::from_millis(10),
+            )
+            .await;
+        assert!(matches!(res, Err(Error::Timeout { command, .. }) if command == "SIZE"));
     }
 }
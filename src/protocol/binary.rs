@@ -1,100 +1,46 @@
-use rand::Rng;
-use tokio::io::AsyncWriteExt;
+use tokio::io::AsyncBufReadExt;
 
-use crate::{Color, Result};
+use crate::{Color, Framebuffer, Result};
 
-use super::{CanvasSize, Proto};
+use super::{read_rgb_frame, Encoding, Framing, Proto, Region};
+
+const GET_PX_BIN: u8 = 0x20;
+const SET_PX_RGB_BIN: u8 = 0x80;
 
 pub struct Protocol {
-    pub count: u64,
+    pub buf: Vec<u8>,
 }
 
 impl Proto for Protocol {
-    async fn send_frame<W: AsyncWriteExt + std::marker::Unpin>(
-        &mut self,
-        writer: &mut W,
-        canvas: u8,
-        color: Color,
-        size: &CanvasSize,
-    ) -> Result<()> {
-        let Color::RGB24(r, g, b) = color;
-        let CanvasSize { x, y } = size;
-        const SET_PX_RGB_BIN: u8 = 0x80;
-        for j in 0..*y {
-            for i in 0..*x {
-                writer
-                    .write_all(&[
-                        SET_PX_RGB_BIN,
-                        canvas,
-                        i.to_be_bytes()[0],
-                        i.to_be_bytes()[1],
-                        j.to_be_bytes()[0],
-                        j.to_be_bytes()[1],
-                        r,
-                        g,
-                        b,
-                    ])
-                    .await?;
-            }
-        }
-        self.count += 1;
-        Ok(())
+    const PROTOCOL: super::Protocol = super::Protocol::BinFlurry;
+    const FRAMING: Framing = Framing::Fixed(9);
+    const GET_FRAMING: Framing = Framing::Fixed(6);
+
+    fn encode_set(buf: &mut Vec<u8>, canvas: u8, x: u16, y: u16, color: Color) {
+        let (r, g, b) = color.rgb();
+        let [x0, x1] = x.to_be_bytes();
+        let [y0, y1] = y.to_be_bytes();
+        buf.extend_from_slice(&[SET_PX_RGB_BIN, canvas, x0, x1, y0, y1, r, g, b]);
+    }
+
+    fn encode_get(buf: &mut Vec<u8>, canvas: u8, x: u16, y: u16) {
+        let [x0, x1] = x.to_be_bytes();
+        let [y0, y1] = y.to_be_bytes();
+        buf.extend_from_slice(&[GET_PX_BIN, canvas, x0, x1, y0, y1]);
     }
 
-    async fn get_frame<W>(&mut self, writer: &mut W, canvas: u8, size: &CanvasSize) -> Result<()>
-    where
-        W: AsyncWriteExt + std::marker::Unpin,
-    {
-        let CanvasSize { x, y } = size;
-        const GET_PX_BIN: u8 = 0x20;
-        for j in 0..*y {
-            for i in 0..*x {
-                writer
-                    .write_all(&[
-                        GET_PX_BIN,
-                        canvas,
-                        i.to_be_bytes()[0],
-                        i.to_be_bytes()[1],
-                        j.to_be_bytes()[0],
-                        j.to_be_bytes()[1],
-                    ])
-                    .await?;
-            }
-        }
-        self.count += 1;
-        Ok(())
+    fn buf(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
     }
 
-    async fn spray_frame<W: AsyncWriteExt + std::marker::Unpin, R: Rng>(
+    async fn read_frame<R: AsyncBufReadExt + std::marker::Unpin>(
         &mut self,
-        writer: &mut W,
-        canvas: u8,
-        rng: &mut R,
-        size: &CanvasSize,
+        reader: &mut R,
+        _canvas: u8,
+        region: &Region,
+        encoding: &Encoding,
+        framebuffer: &Framebuffer,
     ) -> Result<()> {
-        let Color::RGB24(r, g, b) = rng.random();
-        let CanvasSize { x, y } = size;
-        const SET_PX_RGB_BIN: u8 = 0x80;
-        for _j in 0..*y {
-            for _i in 0..*x {
-                let lx = rng.random_range(0..*x);
-                let ly = rng.random_range(0..*y);
-                writer
-                    .write_all(&[
-                        SET_PX_RGB_BIN,
-                        canvas,
-                        lx.to_be_bytes()[0],
-                        lx.to_be_bytes()[1],
-                        ly.to_be_bytes()[0],
-                        ly.to_be_bytes()[1],
-                        r,
-                        g,
-                        b,
-                    ])
-                    .await?;
-            }
-        }
-        self.count += 1;
-        Ok(())
+        read_rgb_frame(reader, region, encoding, framebuffer).await
     }
 }
@@ -0,0 +1,102 @@
+use tokio::io::AsyncBufReadExt;
+
+use crate::{Color, Framebuffer, Result};
+
+use super::{text, Encoding, Framing, Proto, Region};
+
+const SET_PX_RGBA_BIN: &[u8; 2] = b"PB";
+
+pub struct Protocol {
+    pub buf: Vec<u8>,
+}
+
+impl Proto for Protocol {
+    const PROTOCOL: super::Protocol = super::Protocol::BinBreakwater;
+    const FRAMING: Framing = Framing::Fixed(10);
+    const GET_FRAMING: Framing = Framing::Lines;
+    const ALPHA: bool = true;
+
+    fn encode_set(buf: &mut Vec<u8>, _canvas: u8, x: u16, y: u16, color: Color) {
+        let (r, g, b, a) = color.rgba();
+        let [x0, x1] = x.to_le_bytes();
+        let [y0, y1] = y.to_le_bytes();
+        buf.extend_from_slice(SET_PX_RGBA_BIN);
+        buf.extend_from_slice(&[x0, x1, y0, y1, r, g, b, a]);
+    }
+
+    /// Breakwater has no binary get command, pixels are read with text `PX x y`.
+    fn encode_get(buf: &mut Vec<u8>, canvas: u8, x: u16, y: u16) {
+        text::Protocol::encode_get(buf, canvas, x, y);
+    }
+
+    fn buf(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    async fn read_frame<R: AsyncBufReadExt + std::marker::Unpin>(
+        &mut self,
+        reader: &mut R,
+        _canvas: u8,
+        region: &Region,
+        _encoding: &Encoding,
+        framebuffer: &Framebuffer,
+    ) -> Result<()> {
+        text::read_text_frame(reader, region, framebuffer).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+    use crate::CanvasSize;
+
+    #[tokio::test]
+    async fn test_send_frame() {
+        let region = Region::clipped(1, 0, Some(2), Some(1), &CanvasSize { x: 3, y: 1 });
+        let mut protocol = Protocol { buf: Vec::new() };
+        let color = Color::RGB24(0x34, 0xac, 0x49);
+
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"PB\x01\x00\x00\x00\x34\xac\x49\xff")
+            .write(b"PB\x02\x00\x00\x00\x34\xac\x49\xff")
+            .build();
+
+        assert!(protocol
+            .send_frame(&mut writer, 0, color, &region, &Encoding::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_frame_alpha() {
+        let region = Region::clipped(300, 258, Some(1), Some(1), &CanvasSize { x: 400, y: 300 });
+        let mut protocol = Protocol { buf: Vec::new() };
+        let color = Color::RGBA32(0x12, 0x34, 0x56, 0x80);
+
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"PB\x2c\x01\x02\x01\x12\x34\x56\x80")
+            .build();
+
+        assert!(protocol
+            .send_frame(&mut writer, 0, color, &region, &Encoding::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_frame() {
+        let region = Region::clipped(1, 1, Some(2), Some(1), &CanvasSize { x: 3, y: 2 });
+        let mut protocol = Protocol { buf: Vec::new() };
+
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"PX 1 1\n")
+            .write(b"PX 2 1\n")
+            .build();
+
+        assert!(protocol
+            .get_frame(&mut writer, 0, &region, &Encoding::default())
+            .await
+            .is_ok());
+    }
+}
@@ -1,98 +1,158 @@
-use rand::Rng;
-use tokio::io::AsyncWriteExt;
-use ufmt::uwriteln;
+use tokio::io::AsyncBufReadExt;
 
-use crate::{Color, Result};
+use crate::{Color, Framebuffer, Result};
 
-use super::{CanvasSize, Proto};
+use super::{Encoding, Framing, Proto, Region};
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
 
 pub struct Protocol {
-    pub str: String,
-    pub count: u64,
+    pub buf: Vec<u8>,
 }
 
-impl Proto for Protocol {
-    async fn send_frame<W: AsyncWriteExt + std::marker::Unpin>(
-        &mut self,
-        writer: &mut W,
-        _canvas: u8,
-        color: Color,
-        size: &CanvasSize,
-    ) -> Result<()> {
-        let Color::RGB24(r, g, b) = color;
-        let CanvasSize { x, y } = size;
-        for j in 0..*y {
-            for i in 0..*x {
-                uwriteln!(&mut self.str, "PX {} {} {:02X}{:02X}{:02X}", i, j, r, g, b).unwrap();
-                writer.write_all(self.str.as_bytes()).await?;
-                self.str.clear();
-            }
+fn push_dec(buf: &mut Vec<u8>, mut n: u16) {
+    let mut digits = [0; 5];
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
         }
-        self.count += 1;
-        Ok(())
     }
+    buf.extend_from_slice(&digits[i..]);
+}
 
-    async fn get_frame<W: AsyncWriteExt + std::marker::Unpin>(
-        &mut self,
-        writer: &mut W,
-        _canvas: u8,
-        size: &CanvasSize,
-    ) -> Result<()> {
-        let CanvasSize { x, y } = size;
-        for j in 0..*y {
-            for i in 0..*x {
-                uwriteln!(&mut self.str, "PX {} {}", i, j).unwrap();
-                writer.write_all(self.str.as_bytes()).await?;
+fn push_hex(buf: &mut Vec<u8>, b: u8) {
+    buf.extend_from_slice(&[HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+}
+
+impl Proto for Protocol {
+    const PROTOCOL: super::Protocol = super::Protocol::Plaintext;
+    const FRAMING: Framing = Framing::Lines;
+    const ALPHA: bool = true;
+    const OFFSET: bool = true;
+    const GRAY: bool = true;
+
+    /// Colours are sent as `rrggbb`, or `rrggbbaa` if they are transparent.
+    fn encode_set(buf: &mut Vec<u8>, _canvas: u8, x: u16, y: u16, color: Color) {
+        buf.extend_from_slice(b"PX ");
+        push_dec(buf, x);
+        buf.push(b' ');
+        push_dec(buf, y);
+        buf.push(b' ');
+        match color.rgba() {
+            (r, g, b, u8::MAX) => {
+                push_hex(buf, r);
+                push_hex(buf, g);
+                push_hex(buf, b);
+            }
+            (r, g, b, a) => {
+                push_hex(buf, r);
+                push_hex(buf, g);
+                push_hex(buf, b);
+                push_hex(buf, a);
             }
         }
-        self.count += 1;
-        Ok(())
+        buf.push(b'\n');
     }
 
-    async fn spray_frame<W: AsyncWriteExt + std::marker::Unpin, R: Rng>(
+    /// Not every server knows `ww`, so it is only sent when asked for.
+    fn encode_gray(buf: &mut Vec<u8>, _canvas: u8, x: u16, y: u16, w: u8) {
+        buf.extend_from_slice(b"PX ");
+        push_dec(buf, x);
+        buf.push(b' ');
+        push_dec(buf, y);
+        buf.push(b' ');
+        push_hex(buf, w);
+        buf.push(b'\n');
+    }
+
+    fn encode_get(buf: &mut Vec<u8>, _canvas: u8, x: u16, y: u16) {
+        buf.extend_from_slice(b"PX ");
+        push_dec(buf, x);
+        buf.push(b' ');
+        push_dec(buf, y);
+        buf.push(b'\n');
+    }
+
+    fn encode_offset(buf: &mut Vec<u8>, x: u16, y: u16) {
+        buf.extend_from_slice(b"OFFSET ");
+        push_dec(buf, x);
+        buf.push(b' ');
+        push_dec(buf, y);
+        buf.push(b'\n');
+    }
+
+    fn buf(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    async fn read_frame<R: AsyncBufReadExt + std::marker::Unpin>(
         &mut self,
-        writer: &mut W,
+        reader: &mut R,
         _canvas: u8,
-        rng: &mut R,
-        size: &CanvasSize,
+        region: &Region,
+        _encoding: &Encoding,
+        framebuffer: &Framebuffer,
     ) -> Result<()> {
-        let Color::RGB24(r, g, b) = rng.random();
-        let CanvasSize { x, y } = size;
-        for _j in 0..*y {
-            for _i in 0..*x {
-                let lx = rng.random_range(0..*x);
-                let ly = rng.random_range(0..*y);
-                uwriteln!(
-                    &mut self.str,
-                    "PX {} {} {:02X}{:02X}{:02X}",
-                    lx,
-                    ly,
-                    r,
-                    g,
-                    b
-                )
-                .unwrap();
-                writer.write_all(self.str.as_bytes()).await?;
-                self.str.clear();
-            }
+        read_text_frame(reader, region, framebuffer).await
+    }
+}
+
+/// Read `PX x y rrggbb` responses until every pixel of `region` arrived.
+pub(super) async fn read_text_frame<R: AsyncBufReadExt + std::marker::Unpin>(
+    reader: &mut R,
+    region: &Region,
+    framebuffer: &Framebuffer,
+) -> Result<()> {
+    let mut line = String::new();
+    let mut remaining = region.width as usize * region.height as usize;
+    while remaining > 0 {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        // servers may send other lines in between, like errors, skip those
+        if let Some((x, y, color)) = parse_pixel(&line) {
+            framebuffer.set(x, y, color);
+            remaining -= 1;
         }
-        self.count += 1;
-        Ok(())
     }
+    Ok(())
+}
+
+/// Parse a `PX x y rrggbb` command or response, alpha and grayscale colours are accepted too.
+pub(crate) fn parse_pixel(line: &str) -> Option<(u16, u16, Color)> {
+    let mut split = line.split_ascii_whitespace();
+    if split.next()? != "PX" {
+        return None;
+    }
+    let x = split.next()?.parse().ok()?;
+    let y = split.next()?.parse().ok()?;
+    let hex = split.next()?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    let color = match hex.len() {
+        2 => Color::Gray8(channel(0)?),
+        6 => Color::RGB24(channel(0)?, channel(2)?, channel(4)?),
+        8 => Color::RGBA32(channel(0)?, channel(2)?, channel(4)?, channel(6)?),
+        _ => return None,
+    };
+    Some((x, y, color))
 }
 
 #[cfg(test)]
 #[allow(clippy::needless_return)]
 mod tests {
     use super::*;
+    use crate::{protocol::encode_image, CanvasSize, Frame};
+    use test_case::test_case;
 
     #[tokio::test]
     async fn test_send_frame() {
-        let size = CanvasSize { x: 3, y: 2 };
-        let mut protocol = Protocol {
-            str: String::new(),
-            count: 0,
-        };
+        let region = Region::from(&CanvasSize { x: 3, y: 2 });
+        let mut protocol = Protocol { buf: Vec::new() };
         let color = Color::RGB24(0x34, 0xac, 0x49);
 
         let mut writer = tokio_test::io::Builder::new()
@@ -105,8 +165,123 @@ mod tests {
             .build();
 
         assert!(protocol
-            .send_frame(&mut writer, 0, color, &size)
+            .send_frame(&mut writer, 0, color, &region, &Encoding::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_frame_region() {
+        let region = Region::clipped(5, 1, Some(2), Some(8), &CanvasSize { x: 10, y: 3 });
+        let mut protocol = Protocol { buf: Vec::new() };
+        let color = Color::RGB24(0xff, 0x00, 0x10);
+
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"PX 5 1 FF0010\n")
+            .write(b"PX 6 1 FF0010\n")
+            .write(b"PX 5 2 FF0010\n")
+            .write(b"PX 6 2 FF0010\n")
+            .build();
+
+        assert!(protocol
+            .send_frame(&mut writer, 0, color, &region, &Encoding::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_encoded_image_clipped() {
+        let region = Region::clipped(3, 2, None, None, &CanvasSize { x: 4, y: 4 });
+        let mut protocol = Protocol { buf: Vec::new() };
+        let image = Frame::from_rgb(2, 2, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]).unwrap();
+
+        let mut writer = tokio_test::io::Builder::new()
+            .write(b"PX 3 2 010203\n")
+            .write(b"PX 3 3 070809\n")
+            .build();
+
+        let mut buf = Vec::new();
+        encode_image::<Protocol>(&mut buf, 0, &image, &region, &Encoding::default()).unwrap();
+        assert!(protocol.send_encoded(&mut writer, &buf).await.is_ok());
+    }
+
+    #[test_case(false, [b"PX 0 0 7F7F7F\n", b"PX 0 0 121212\n"] ; "full")]
+    #[test_case(true, [b"PX 0 0 7F\n", b"PX 0 0 12\n"] ; "gray")]
+    #[tokio::test]
+    async fn test_send_frame_shortest_color(gray: bool, grays: [&[u8]; 2]) {
+        let region = Region::from(&CanvasSize { x: 1, y: 1 });
+        let mut protocol = Protocol { buf: Vec::new() };
+        let encoding = Encoding {
+            gray,
+            ..Default::default()
+        };
+
+        let mut writer = tokio_test::io::Builder::new()
+            .write(grays[0])
+            .write(grays[1])
+            .write(b"PX 0 0 123456\n")
+            .write(b"PX 0 0 12345680\n")
+            .build();
+
+        for color in [
+            Color::Gray8(0x7f),
+            Color::RGB24(0x12, 0x12, 0x12),
+            Color::RGBA32(0x12, 0x34, 0x56, 0xff),
+            Color::RGBA32(0x12, 0x34, 0x56, 0x80),
+        ] {
+            protocol
+                .send_frame(&mut writer, 0, color, &region, &encoding)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_frame_offset() {
+        let region = Region::clipped(
+            150,
+            1020,
+            Some(101),
+            Some(1),
+            &CanvasSize { x: 1000, y: 1100 },
+        );
+        let mut protocol = Protocol { buf: Vec::new() };
+        let encoding = Encoding {
+            offset: true,
+            ..Default::default()
+        };
+
+        let mut expected = b"OFFSET 150 1020\n".to_vec();
+        for x in 0..100 {
+            expected.extend_from_slice(format!("PX {} 0 ABABAB\n", x).as_bytes());
+        }
+        expected.extend_from_slice(b"OFFSET 250 1020\nPX 0 0 ABABAB\n");
+        let mut writer = tokio_test::io::Builder::new().write(&expected).build();
+
+        protocol
+            .send_frame(&mut writer, 0, Color::Gray8(0xab), &region, &encoding)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let region = Region::clipped(1, 0, Some(2), Some(1), &CanvasSize { x: 3, y: 1 });
+        let framebuffer = Framebuffer::new(&CanvasSize { x: 3, y: 1 });
+        let mut protocol = Protocol { buf: Vec::new() };
+
+        let reader = tokio_test::io::Builder::new()
+            .read(b"PX 2 0 123456\nERROR unknown command\n")
+            .read(b"PX 1 0 AB\n")
+            .build();
+        let mut reader = tokio::io::BufReader::new(reader);
+
+        assert!(protocol
+            .read_frame(&mut reader, 0, &region, &Encoding::default(), &framebuffer)
             .await
             .is_ok());
+        assert_eq!(framebuffer.get(0, 0), Color::RGB24(0, 0, 0));
+        assert_eq!(framebuffer.get(1, 0), Color::RGB24(0xab, 0xab, 0xab));
+        assert_eq!(framebuffer.get(2, 0), Color::RGB24(0x12, 0x34, 0x56));
     }
 }
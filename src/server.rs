@@ -0,0 +1,543 @@
+//! A small in-memory pixelflut server, meant for tests and local demos.
+
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpListener,
+};
+use ufmt::uwriteln;
+
+use crate::{text::parse_pixel, CanvasSize, Color, Framebuffer, Protocol, Result};
+
+const HELP_TEXT: &[u8] = b"HELP tsunami mock server
+HELP SIZE, CANVAS n, OFFSET x y, PX x y, PX x y rrggbb|rrggbbaa|ww, PROTOCOL binary|palette
+HELP PB followed by x and y as u16 LE and rgba
+";
+
+pub struct Server {
+    protocol: Protocol,
+    canvas: Arc<Framebuffer>,
+}
+
+impl Server {
+    pub fn new(protocol: Protocol, size: CanvasSize) -> Self {
+        Self {
+            protocol,
+            canvas: Arc::new(Framebuffer::new(&size)),
+        }
+    }
+
+    /// The pixels drawn so far, all canvas numbers share it.
+    pub fn canvas(&self) -> Arc<Framebuffer> {
+        self.canvas.clone()
+    }
+
+    /// Accept clients until the listener fails.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let protocol = self.protocol;
+            let canvas = self.canvas.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = socket.into_split();
+                let mut reader = BufReader::new(reader);
+                let mut writer = BufWriter::new(writer);
+                let _ = handle(protocol, &canvas, &mut reader, &mut writer).await;
+            });
+        }
+    }
+}
+
+/// Serve one client, until it disconnects.
+///
+/// Flutties clients talk binary from the start, all others start out
+/// with text and may switch over with a `PROTOCOL` command.
+pub async fn handle<R, W>(
+    protocol: Protocol,
+    canvas: &Framebuffer,
+    reader: &mut BufReader<R>,
+    writer: &mut W,
+) -> Result<()>
+where
+    R: AsyncReadExt + std::marker::Unpin,
+    W: AsyncWriteExt + std::marker::Unpin,
+{
+    match protocol {
+        Protocol::BinFlutties => handle_flutties(canvas, reader, writer).await,
+        _ => handle_text(canvas, reader, writer).await,
+    }
+}
+
+async fn handle_text<R, W>(
+    canvas: &Framebuffer,
+    reader: &mut BufReader<R>,
+    writer: &mut W,
+) -> Result<()>
+where
+    R: AsyncReadExt + std::marker::Unpin,
+    W: AsyncWriteExt + std::marker::Unpin,
+{
+    let mut line = String::new();
+    let mut response = String::new();
+    let (mut ox, mut oy) = (0u16, 0u16);
+    let mut pb = [0; 8];
+    loop {
+        line.clear();
+        // breakwater's binary `PB` command can show up between text lines
+        match reader.read_u8().await {
+            Ok(b'P') => match reader.read_u8().await? {
+                b'B' => {
+                    reader.read_exact(&mut pb).await?;
+                    let x = u16::from_le_bytes([pb[0], pb[1]]);
+                    let y = u16::from_le_bytes([pb[2], pb[3]]);
+                    canvas.set(x, y, Color::RGBA32(pb[4], pb[5], pb[6], pb[7]));
+                    if reader.buffer().is_empty() {
+                        writer.flush().await?;
+                    }
+                    continue;
+                }
+                second => {
+                    line.push('P');
+                    line.push(second as char);
+                }
+            },
+            Ok(first) => line.push(first as char),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        if !line.ends_with('\n') {
+            reader.read_line(&mut line).await?;
+        }
+        let mut split = line.split_ascii_whitespace();
+        match (split.next(), split.next(), split.next(), split.next()) {
+            (Some("SIZE"), ..) => {
+                uwriteln!(&mut response, "SIZE {} {}", canvas.width, canvas.height).unwrap();
+            }
+            (Some("HELP"), ..) => writer.write_all(HELP_TEXT).await?,
+            (Some("CANVAS"), Some(_), None, _) => {}
+            (Some("OFFSET"), Some(x), Some(y), None) => match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => (ox, oy) = (x, y),
+                _ => response.push_str("ERROR invalid offset\n"),
+            },
+            (Some("PX"), Some(_), Some(_), Some(_)) => {
+                if let Some((x, y, color)) = parse_pixel(&line) {
+                    canvas.set(x.saturating_add(ox), y.saturating_add(oy), color);
+                }
+            }
+            (Some("PX"), Some(x), Some(y), None) => match (x.parse::<u16>(), y.parse::<u16>()) {
+                (Ok(x), Ok(y))
+                    if x.saturating_add(ox) < canvas.width
+                        && y.saturating_add(oy) < canvas.height =>
+                {
+                    let (x, y) = (x + ox, y + oy);
+                    let (r, g, b) = canvas.get(x, y).rgb();
+                    uwriteln!(&mut response, "PX {} {} {:02X}{:02X}{:02X}", x, y, r, g, b).unwrap();
+                }
+                _ => response.push_str("ERROR coordinates out of range\n"),
+            },
+            (Some("PROTOCOL"), Some("binary"), None, _) => {
+                writer.flush().await?;
+                return handle_binary(canvas, reader, writer, false).await;
+            }
+            (Some("PROTOCOL"), Some("palette"), None, _) => {
+                writer.flush().await?;
+                return handle_binary(canvas, reader, writer, true).await;
+            }
+            _ => response.push_str("ERROR unknown command\n"),
+        }
+        if !response.is_empty() {
+            writer.write_all(response.as_bytes()).await?;
+            response.clear();
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+/// The flurry binary protocol, palette colours are a grayscale ramp.
+async fn handle_binary<R, W>(
+    canvas: &Framebuffer,
+    reader: &mut BufReader<R>,
+    writer: &mut W,
+    palette: bool,
+) -> Result<()>
+where
+    R: AsyncReadExt + std::marker::Unpin,
+    W: AsyncWriteExt + std::marker::Unpin,
+{
+    const SIZE_BIN: u8 = 115;
+    const GET_PX_BIN: u8 = 0x20;
+    const SET_PX_PALETTE_BIN: u8 = 0x21;
+    const SET_PX_RGB_BIN: u8 = 0x80;
+    let mut buf = [0; 8];
+    loop {
+        let command = match reader.read_u8().await {
+            Ok(command) => command,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        match command {
+            SIZE_BIN => {
+                reader.read_u8().await?;
+                writer.write_u16(canvas.width).await?;
+                writer.write_u16(canvas.height).await?;
+            }
+            GET_PX_BIN => {
+                reader.read_exact(&mut buf[..5]).await?;
+                let x = u16::from_be_bytes([buf[1], buf[2]]);
+                let y = u16::from_be_bytes([buf[3], buf[4]]);
+                writer.write_all(&rgb_at(canvas, x, y)).await?;
+            }
+            SET_PX_PALETTE_BIN if palette => {
+                reader.read_exact(&mut buf[..6]).await?;
+                let x = u16::from_be_bytes([buf[1], buf[2]]);
+                let y = u16::from_be_bytes([buf[3], buf[4]]);
+                canvas.set(x, y, Color::RGB24(buf[5], buf[5], buf[5]));
+            }
+            SET_PX_RGB_BIN if !palette => {
+                reader.read_exact(&mut buf[..8]).await?;
+                let x = u16::from_be_bytes([buf[1], buf[2]]);
+                let y = u16::from_be_bytes([buf[3], buf[4]]);
+                canvas.set(x, y, Color::RGB24(buf[5], buf[6], buf[7]));
+            }
+            // there is no way to resync a binary stream
+            _ => return Ok(()),
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+async fn handle_flutties<R, W>(
+    canvas: &Framebuffer,
+    reader: &mut BufReader<R>,
+    writer: &mut W,
+) -> Result<()>
+where
+    R: AsyncReadExt + std::marker::Unpin,
+    W: AsyncWriteExt + std::marker::Unpin,
+{
+    const SIZE_BIN: u8 = 32;
+    const GET_PX_BIN: u8 = 128;
+    const SET_PX_RGB_BIN: u8 = 176;
+    let mut buf = [0; 7];
+    loop {
+        let command = match reader.read_u8().await {
+            Ok(command) => command,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        match command {
+            SIZE_BIN => {
+                reader.read_u8().await?;
+                writer.write_u16(canvas.width).await?;
+                writer.write_u16(canvas.height).await?;
+            }
+            GET_PX_BIN..SET_PX_RGB_BIN => {
+                reader.read_exact(&mut buf[..4]).await?;
+                let x = u16::from_le_bytes([buf[0], buf[1]]);
+                let y = u16::from_le_bytes([buf[2], buf[3]]);
+                writer.write_all(&rgb_at(canvas, x, y)).await?;
+            }
+            SET_PX_RGB_BIN.. => {
+                reader.read_exact(&mut buf[..7]).await?;
+                let x = u16::from_le_bytes([buf[0], buf[1]]);
+                let y = u16::from_le_bytes([buf[2], buf[3]]);
+                canvas.set(x, y, Color::RGB24(buf[4], buf[5], buf[6]));
+            }
+            _ => return Ok(()),
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+fn rgb_at(canvas: &Framebuffer, x: u16, y: u16) -> [u8; 3] {
+    if x >= canvas.width || y >= canvas.height {
+        return [0; 3];
+    }
+    let (r, g, b) = canvas.get(x, y).rgb();
+    [r, g, b]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use test_case::test_case;
+    use tokio::net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    };
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    use super::*;
+    use crate::{
+        binary, breakwater, flutties, palette, text, Encoding, Endpoint, Frame, PixelOrder, Proto,
+        Region, Transport,
+    };
+
+    async fn draw_and_read<P, R, W>(
+        proto: &mut P,
+        (reader, writer): (R, W),
+        protocol: Protocol,
+        color: Color,
+        encoding: Encoding,
+    ) -> Framebuffer
+    where
+        P: Proto,
+        R: AsyncReadExt + std::marker::Unpin,
+        W: AsyncWriteExt + std::marker::Unpin,
+    {
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let size = protocol
+            .preamble(&mut writer, &mut reader, 0, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(size, CanvasSize { x: 6, y: 4 });
+
+        let region = Region::clipped(1, 2, Some(3), Some(2), &size);
+        let framebuffer = Framebuffer::new(&size);
+        proto
+            .send_frame(&mut writer, 0, color, &region, &encoding)
+            .await
+            .unwrap();
+        proto
+            .get_frame(&mut writer, 0, &region, &encoding)
+            .await
+            .unwrap();
+        writer.flush().await.unwrap();
+        proto
+            .read_frame(&mut reader, 0, &region, &encoding, &framebuffer)
+            .await
+            .unwrap();
+        framebuffer
+    }
+
+    const GRAY: Color = Color::RGB24(0x40, 0x40, 0x40);
+    const TEAL: Color = Color::RGB24(0x12, 0x34, 0x56);
+    const OFFSET: Encoding = Encoding {
+        offset: true,
+        gray: false,
+        order: PixelOrder::Scanline,
+        seed: 0,
+    };
+    const SHORT_GRAY: Encoding = Encoding {
+        offset: false,
+        gray: true,
+        order: PixelOrder::Scanline,
+        seed: 0,
+    };
+    const RANDOM: Encoding = Encoding {
+        offset: false,
+        gray: false,
+        order: PixelOrder::Random,
+        seed: 7,
+    };
+
+    #[test_case(Protocol::Plaintext, TEAL, Encoding::default() ; "plaintext")]
+    #[test_case(Protocol::Plaintext, TEAL, OFFSET ; "plaintext offset")]
+    #[test_case(Protocol::Plaintext, GRAY, SHORT_GRAY ; "plaintext short gray")]
+    #[test_case(Protocol::BinFlurry, TEAL, Encoding::default() ; "binary")]
+    #[test_case(Protocol::BinFlurry, TEAL, RANDOM ; "binary random order")]
+    #[test_case(Protocol::BinFlutties, TEAL, Encoding::default() ; "flutties")]
+    #[test_case(Protocol::Palette, GRAY, Encoding::default() ; "palette")]
+    #[test_case(Protocol::BinBreakwater, TEAL, Encoding::default() ; "breakwater")]
+    #[tokio::test]
+    async fn test_round_trip(protocol: Protocol, color: Color, encoding: Encoding) {
+        let server = Server::new(protocol, CanvasSize { x: 6, y: 4 });
+        let canvas = server.canvas();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let stream = TcpStream::connect(addr).await.unwrap().into_split();
+        let framebuffer = match_parser!(proto: protocol => {
+            break draw_and_read(&mut proto, stream, protocol, color, encoding).await;
+        });
+
+        assert_drawn(&framebuffer, &canvas, color);
+    }
+
+    #[test_case(Protocol::Plaintext, TEAL ; "plaintext")]
+    #[test_case(Protocol::BinFlurry, TEAL ; "binary")]
+    #[test_case(Protocol::BinFlutties, TEAL ; "flutties")]
+    #[test_case(Protocol::Palette, GRAY ; "palette")]
+    #[test_case(Protocol::BinBreakwater, TEAL ; "breakwater")]
+    #[tokio::test]
+    async fn test_websocket_round_trip(protocol: Protocol, color: Color) {
+        let server = Server::new(protocol, CanvasSize { x: 6, y: 4 });
+        let canvas = server.canvas();
+        let addr = serve(server).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint {
+            transport: Transport::WebSocket,
+            ..Endpoint::tcp(&listener.local_addr().unwrap().to_string())
+        };
+        tokio::spawn(relay(listener, addr));
+
+        let socket = endpoint.connect(protocol.framing()).await.unwrap();
+        let round_trip = async {
+            match_parser!(proto: protocol => {
+                break draw_and_read(&mut proto, socket, protocol, color, Encoding::default()).await;
+            })
+        };
+        let framebuffer = tokio::time::timeout(Duration::from_secs(5), round_trip)
+            .await
+            .expect("commands got stuck in the websocket writer");
+
+        assert_drawn(&framebuffer, &canvas, color);
+    }
+
+    /// Check that `color` was drawn into the region `draw_and_read` uses, and
+    /// that it was read back.
+    fn assert_drawn(framebuffer: &Framebuffer, canvas: &Framebuffer, color: Color) {
+        for y in 0..4 {
+            for x in 0..6 {
+                let inside = (1..4).contains(&x) && (2..4).contains(&y);
+                let expected = if inside { color } else { Color::RGB24(0, 0, 0) };
+                assert_eq!(framebuffer.get(x, y), expected);
+                assert_eq!(canvas.get(x, y), expected);
+            }
+        }
+    }
+
+    /// Accept one websocket client on `listener` and pass its messages on to
+    /// the tcp server at `addr`, which takes them as one stream of bytes.
+    async fn relay(listener: TcpListener, addr: String) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (mut sink, mut stream) = accept_async(socket).await.unwrap().split();
+        let (mut reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let upstream = async move {
+            while let Some(Ok(message)) = stream.next().await {
+                writer.write_all(&message.into_data()).await.unwrap();
+            }
+        };
+        let downstream = async move {
+            let mut buf = [0; 1024];
+            loop {
+                let n = reader.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                let message = Message::binary(buf[..n].to_vec());
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::join!(upstream, downstream);
+    }
+
+    #[test_case(Protocol::Plaintext ; "plaintext")]
+    #[test_case(Protocol::BinFlurry ; "binary")]
+    #[test_case(Protocol::BinFlutties ; "flutties")]
+    #[test_case(Protocol::BinBreakwater ; "breakwater")]
+    #[tokio::test]
+    async fn test_snapshot_restore(protocol: Protocol) {
+        let size = CanvasSize { x: 6, y: 4 };
+        let region = Region::clipped(1, 1, Some(4), Some(2), &size);
+        let source = Server::new(protocol, size);
+        for (i, (x, y)) in [(1, 1), (2, 1), (4, 2)].into_iter().enumerate() {
+            source
+                .canvas()
+                .set(x, y, Color::RGB24(0x10 * i as u8, 0x20, 0x30));
+        }
+        let expected = source.canvas().to_frame().crop(1, 1, 4, 2);
+        let source = serve(source).await;
+        let restored = Server::new(protocol, size);
+        let canvas = restored.canvas();
+        let restored = serve(restored).await;
+        let file = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+
+        match_parser!(proto: protocol => {
+            let (mut reader, mut writer) = connect(protocol, &source).await;
+            let framebuffer = Framebuffer::new(&size);
+            let encoding = Encoding::default();
+            proto.get_frame(&mut writer, 0, &region, &encoding).await.unwrap();
+            writer.flush().await.unwrap();
+            proto
+                .read_frame(&mut reader, 0, &region, &encoding, &framebuffer)
+                .await
+                .unwrap();
+            let snapshot = framebuffer.to_frame().crop(region.x, region.y, region.width, region.height);
+            snapshot.save(file.path()).unwrap();
+
+            let image = Frame::load(file.path()).unwrap();
+            let (mut reader, mut writer) = connect(protocol, &restored).await;
+            let bands = protocol.encode_image_bands(0, &image, &region, &encoding, 1).unwrap();
+            proto.send_encoded(&mut writer, &bands[0]).await.unwrap();
+            // reading the restored region back makes sure the server drew all of it
+            let framebuffer = Framebuffer::new(&size);
+            proto.get_frame(&mut writer, 0, &region, &encoding).await.unwrap();
+            writer.flush().await.unwrap();
+            proto
+                .read_frame(&mut reader, 0, &region, &encoding, &framebuffer)
+                .await
+                .unwrap();
+            break;
+        });
+
+        let restored = canvas.to_frame().crop(1, 1, 4, 2);
+        for y in 0..2 {
+            for x in 0..4 {
+                assert_eq!(restored.get(x, y), expected.get(x, y));
+            }
+        }
+    }
+
+    /// Serve `server` on a free port, returns its address.
+    async fn serve(server: Server) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { server.serve(listener).await });
+        addr
+    }
+
+    /// Connect to `addr` and run the preamble.
+    async fn connect(
+        protocol: Protocol,
+        addr: &str,
+    ) -> (BufReader<OwnedReadHalf>, BufWriter<OwnedWriteHalf>) {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+        protocol
+            .preamble(&mut writer, &mut reader, 0, Duration::from_secs(1))
+            .await
+            .unwrap();
+        (reader, writer)
+    }
+
+    #[test_case(Protocol::Plaintext, Protocol::BinFlurry ; "text server")]
+    #[test_case(Protocol::BinFlutties, Protocol::BinFlutties ; "flutties server")]
+    #[tokio::test]
+    async fn test_detect(served: Protocol, expected: Protocol) {
+        let addr = serve(Server::new(served, CanvasSize { x: 6, y: 4 })).await;
+
+        let detected = Protocol::detect(&Endpoint::tcp(&addr), 0, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(detected, expected);
+    }
+
+    #[tokio::test]
+    async fn test_supports_offset() {
+        let addr = serve(Server::new(Protocol::Plaintext, CanvasSize { x: 6, y: 4 })).await;
+
+        let timeout = Duration::from_millis(200);
+        assert!(Protocol::Plaintext
+            .supports_offset(&Endpoint::tcp(&addr), timeout)
+            .await
+            .unwrap());
+        assert!(!Protocol::BinFlurry
+            .supports_offset(&Endpoint::tcp(&addr), timeout)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    fmt::Display,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+
+use crate::{Error, Result};
+
+/// Most frame times each worker keeps for percentiles.
+const FRAME_SAMPLES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WorkerState {
+    Connecting,
+    Running,
+    Done,
+    Failed,
+}
+
+impl WorkerState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, WorkerState::Done | WorkerState::Failed)
+    }
+}
+
+impl Display for WorkerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkerState::Connecting => write!(f, "connecting"),
+            WorkerState::Running => write!(f, "running"),
+            WorkerState::Done => write!(f, "done"),
+            WorkerState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Counters of a single worker, updated while it runs.
+#[derive(Debug)]
+pub struct WorkerStats {
+    state: AtomicU8,
+    pub frames: AtomicU64,
+    pub pixels: AtomicU64,
+    pub bytes: AtomicU64,
+    pub reconnects: AtomicU64,
+    pub errors: AtomicU64,
+    frame_times: Mutex<FrameTimes>,
+    /// The frame times of the whole run, which every worker feeds
+    all_frame_times: Arc<Mutex<FrameTimes>>,
+}
+
+/// How long frames took, in microseconds. Averages are exact, percentiles
+/// come from a uniform sample of at most `FRAME_SAMPLES` frames, so long
+/// runs do not keep every frame around.
+#[derive(Debug, Clone, Default)]
+pub struct FrameTimes {
+    samples: Vec<u32>,
+    count: u64,
+    sum: u64,
+}
+
+impl FrameTimes {
+    pub fn record(&mut self, micros: u32) {
+        self.count += 1;
+        self.sum += micros as u64;
+        if self.samples.len() < FRAME_SAMPLES {
+            self.samples.push(micros);
+        } else {
+            // reservoir sampling, every frame so far is kept with the same chance
+            let i = rand::random_range(0..self.count);
+            if i < FRAME_SAMPLES as u64 {
+                self.samples[i as usize] = micros;
+            }
+        }
+    }
+}
+
+/// A point in time copy of the counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub frames: u64,
+    pub pixels: u64,
+    pub bytes: u64,
+    pub reconnects: u64,
+    pub errors: u64,
+}
+
+impl WorkerStats {
+    pub fn state(&self) -> WorkerState {
+        match self.state.load(Ordering::Relaxed) {
+            0 => WorkerState::Connecting,
+            1 => WorkerState::Running,
+            2 => WorkerState::Done,
+            _ => WorkerState::Failed,
+        }
+    }
+
+    pub fn set_state(&self, state: WorkerState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// Record a finished frame, `frames` is how many the worker finished so far.
+    pub fn frame_done(&self, frames: u64, pixels: u64, took: Duration) {
+        self.frames.store(frames, Ordering::Relaxed);
+        self.pixels.fetch_add(pixels, Ordering::Relaxed);
+        let micros = took.as_micros().min(u32::MAX as u128) as u32;
+        self.frame_times.lock().unwrap().record(micros);
+        self.all_frame_times.lock().unwrap().record(micros);
+    }
+
+    /// Record a lost connection that is about to be retried.
+    pub fn reconnecting(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        self.set_state(WorkerState::Connecting);
+    }
+
+    pub fn failed(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.set_state(WorkerState::Failed);
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            frames: self.frames.load(Ordering::Relaxed),
+            pixels: self.pixels.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for WorkerStats {
+    fn default() -> Self {
+        Self {
+            state: AtomicU8::new(WorkerState::Connecting as u8),
+            frames: AtomicU64::new(0),
+            pixels: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            frame_times: Mutex::new(FrameTimes::default()),
+            all_frame_times: Arc::default(),
+        }
+    }
+}
+
+/// Statistics of all workers of a run.
+#[derive(Debug)]
+pub struct Stats {
+    pub started: Instant,
+    pub workers: Vec<Arc<WorkerStats>>,
+    /// Frame times of all workers in one sample, so busy workers weigh more
+    frame_times: Arc<Mutex<FrameTimes>>,
+    finished: AtomicBool,
+}
+
+impl Stats {
+    pub fn new(workers: usize) -> Self {
+        let frame_times = Arc::<Mutex<FrameTimes>>::default();
+        Self {
+            started: Instant::now(),
+            workers: (0..workers)
+                .map(|_| {
+                    Arc::new(WorkerStats {
+                        all_frame_times: frame_times.clone(),
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+            frame_times,
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    pub fn total(&self) -> Counters {
+        self.workers
+            .iter()
+            .map(|w| w.counters())
+            .fold(Counters::default(), |a, b| Counters {
+                frames: a.frames + b.frames,
+                pixels: a.pixels + b.pixels,
+                bytes: a.bytes + b.bytes,
+                reconnects: a.reconnects + b.reconnects,
+                errors: a.errors + b.errors,
+            })
+    }
+
+    /// Mark the run as over, even if some workers never reported back.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn all_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+            || self.workers.iter().all(|w| w.state().is_finished())
+    }
+}
+
+/// Counts the bytes that make it to the underlying writer.
+pub struct CountingWriter<W> {
+    inner: W,
+    stats: Arc<WorkerStats>,
+}
+
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W, stats: Arc<WorkerStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.stats.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Summary of a run, for printing or comparing runs later on.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub seconds: f64,
+    pub total: WorkerReport,
+    pub workers: Vec<WorkerReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerReport {
+    pub frames: u64,
+    pub pixels: u64,
+    pub bytes: u64,
+    pub reconnects: u64,
+    pub errors: u64,
+    pub frames_per_second: f64,
+    pub pixels_per_second: f64,
+    pub bytes_per_second: f64,
+    /// Frame times in milliseconds
+    pub frame_avg: f64,
+    pub frame_p50: f64,
+    pub frame_p99: f64,
+}
+
+impl WorkerReport {
+    fn new(counters: Counters, frame_times: FrameTimes, seconds: f64) -> Self {
+        let mut samples = frame_times.samples;
+        samples.sort_unstable();
+        let percentile = |q: f64| match samples.len() {
+            0 => 0.0,
+            n => samples[((n - 1) as f64 * q).round() as usize] as f64 / 1000.0,
+        };
+        let frame_avg = match frame_times.count {
+            0 => 0.0,
+            n => frame_times.sum as f64 / n as f64 / 1000.0,
+        };
+        let seconds = seconds.max(f64::EPSILON);
+        Self {
+            frames: counters.frames,
+            pixels: counters.pixels,
+            bytes: counters.bytes,
+            reconnects: counters.reconnects,
+            errors: counters.errors,
+            frames_per_second: counters.frames as f64 / seconds,
+            pixels_per_second: counters.pixels as f64 / seconds,
+            bytes_per_second: counters.bytes as f64 / seconds,
+            frame_avg,
+            frame_p50: percentile(0.5),
+            frame_p99: percentile(0.99),
+        }
+    }
+}
+
+impl Report {
+    pub fn new(stats: &Stats) -> Self {
+        let seconds = stats.started.elapsed().as_secs_f64();
+        let workers = stats
+            .workers
+            .iter()
+            .map(|w| {
+                let times = w.frame_times.lock().unwrap().clone();
+                WorkerReport::new(w.counters(), times, seconds)
+            })
+            .collect();
+        let times = stats.frame_times.lock().unwrap().clone();
+        let total = WorkerReport::new(stats.total(), times, seconds);
+        Self {
+            seconds,
+            total,
+            workers,
+        }
+    }
+
+    /// Write the report as json or csv, depending on the extension of `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::to_string_pretty(self)
+                .map_err(|e| Error::Custom(format!("could not serialize report: {}", e)))?,
+            Some("csv") => self.to_csv(),
+            _ => {
+                return Err(Error::InvalidArgs(format!(
+                    "report file {} should end in .json or .csv",
+                    path.display()
+                )))
+            }
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "worker,seconds,frames,pixels,bytes,reconnects,errors,frames_per_second,\
+            pixels_per_second,bytes_per_second,frame_avg_ms,frame_p50_ms,frame_p99_ms\n",
+        );
+        let rows = self
+            .workers
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (i.to_string(), w))
+            .chain(std::iter::once(("total".to_string(), &self.total)));
+        for (name, w) in rows {
+            csv.push_str(&format!(
+                "{},{:.3},{},{},{},{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
+                name,
+                self.seconds,
+                w.frames,
+                w.pixels,
+                w.bytes,
+                w.reconnects,
+                w.errors,
+                w.frames_per_second,
+                w.pixels_per_second,
+                w.bytes_per_second,
+                w.frame_avg,
+                w.frame_p50,
+                w.frame_p99
+            ));
+        }
+        csv
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let t = &self.total;
+        writeln!(f, "Run summary after {:.1}s", self.seconds)?;
+        writeln!(
+            f,
+            "  {} frames, {} pixels, {} bytes",
+            t.frames,
+            human(t.pixels as f64),
+            human(t.bytes as f64)
+        )?;
+        writeln!(
+            f,
+            "  {} frames/s, {} pixels/s, {} bytes/s",
+            human(t.frames_per_second),
+            human(t.pixels_per_second),
+            human(t.bytes_per_second)
+        )?;
+        writeln!(
+            f,
+            "  frame time avg {:.2}ms, p50 {:.2}ms, p99 {:.2}ms",
+            t.frame_avg, t.frame_p50, t.frame_p99
+        )?;
+        writeln!(f, "  {} reconnects, {} errors", t.reconnects, t.errors)?;
+        writeln!(
+            f,
+            "{:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+            "worker",
+            "frames",
+            "pixels/s",
+            "bytes/s",
+            "avg ms",
+            "p50 ms",
+            "p99 ms",
+            "reconnects",
+            "errors"
+        )?;
+        for (i, w) in self.workers.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>6} {:>10} {:>10} {:>10} {:>10.2} {:>10.2} {:>10.2} {:>10} {:>8}",
+                i,
+                w.frames,
+                human(w.pixels_per_second),
+                human(w.bytes_per_second),
+                w.frame_avg,
+                w.frame_p50,
+                w.frame_p99,
+                w.reconnects,
+                w.errors
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Format a number with a metric suffix.
+pub fn human(value: f64) -> String {
+    const SUFFIXES: [&str; 5] = ["", "k", "M", "G", "T"];
+    let mut value = value;
+    let mut suffix = 0;
+    while value >= 1000.0 && suffix < SUFFIXES.len() - 1 {
+        value /= 1000.0;
+        suffix += 1;
+    }
+    format!("{:.1}{}", value, SUFFIXES[suffix])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_report() {
+        let counters = Counters {
+            frames: 100,
+            pixels: 1000,
+            bytes: 5000,
+            reconnects: 1,
+            errors: 2,
+        };
+        let mut frame_times = FrameTimes::default();
+        for ms in (1..=100).rev() {
+            frame_times.record(ms * 1000);
+        }
+        let report = WorkerReport::new(counters, frame_times, 10.0);
+
+        assert_eq!(report.frames_per_second, 10.0);
+        assert_eq!(report.bytes_per_second, 500.0);
+        assert_eq!(report.frame_avg, 50.5);
+        assert_eq!(report.frame_p50, 51.0);
+        assert_eq!(report.frame_p99, 99.0);
+    }
+
+    #[test]
+    fn test_total_weighs_busy_workers() {
+        let stats = Stats::new(2);
+        for frames in 1..=10 {
+            stats.workers[0].frame_done(frames, 1, Duration::from_millis(500));
+        }
+        for frames in 1..=100_000 {
+            stats.workers[1].frame_done(frames, 1, Duration::from_millis(2));
+        }
+
+        let report = Report::new(&stats);
+        assert_eq!(report.workers[0].frame_p50, 500.0);
+        assert_eq!(report.total.frame_p50, 2.0);
+        assert_eq!(report.total.frame_p99, 2.0);
+    }
+
+    #[test]
+    fn test_frame_times_bounded() {
+        let mut frame_times = FrameTimes::default();
+        for micros in 0..100_000 {
+            frame_times.record(micros);
+        }
+        assert_eq!(frame_times.samples.len(), FRAME_SAMPLES);
+        assert_eq!(frame_times.count, 100_000);
+
+        let report = WorkerReport::new(Counters::default(), frame_times, 1.0);
+        assert_eq!(report.frame_avg, 49.9995);
+        // a uniform sample puts the median close to the middle
+        assert!(
+            (40.0..60.0).contains(&report.frame_p50),
+            "{}",
+            report.frame_p50
+        );
+    }
+}
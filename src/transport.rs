@@ -0,0 +1,333 @@
+//! The connections that commands travel over.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpStream, UdpSocket, UnixStream},
+};
+
+use crate::{Framing, Result};
+
+mod tls;
+mod websocket;
+
+pub use tls::Tls;
+
+/// Fits a datagram in a 1500 byte ethernet frame, after the IPv4 and UDP headers.
+pub const DEFAULT_MTU: usize = 1472;
+
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// Pack commands into datagrams, the server never replies
+    Udp,
+    /// Batch commands into websocket messages, picked for `ws://` and `wss://` hosts
+    #[serde(rename = "websocket")]
+    #[value(name = "websocket")]
+    WebSocket,
+    /// A unix domain socket on this machine, picked for `unix:` hosts
+    Unix,
+}
+
+impl Transport {
+    /// Whether the server can reply, which reading and the preamble need.
+    pub fn replies(&self) -> bool {
+        match self {
+            Transport::Tcp | Transport::WebSocket | Transport::Unix => true,
+            Transport::Udp => false,
+        }
+    }
+
+    /// The transport that `host` asks for with its scheme, if any.
+    pub fn for_host(self, host: &str) -> Transport {
+        if host.starts_with("ws://") || host.starts_with("wss://") {
+            Transport::WebSocket
+        } else if host.starts_with("unix:") {
+            Transport::Unix
+        } else {
+            self
+        }
+    }
+}
+
+/// Where to connect to, and how.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub host: String,
+    pub transport: Transport,
+    /// Largest datagram payload, only used for udp
+    pub mtu: usize,
+    /// Send text instead of binary websocket messages
+    pub ws_text: bool,
+    /// Wrap stream connections in TLS
+    pub tls: Option<Tls>,
+}
+
+impl Endpoint {
+    /// Plain tcp to `host`.
+    pub fn tcp(host: &str) -> Endpoint {
+        Endpoint {
+            host: host.to_string(),
+            transport: Transport::Tcp,
+            mtu: DEFAULT_MTU,
+            ws_text: false,
+            tls: None,
+        }
+    }
+
+    /// The same host over a transport that gets replies, udp asks over tcp.
+    pub fn with_replies(&self) -> Endpoint {
+        match self.transport.replies() {
+            true => self.clone(),
+            false => Endpoint {
+                transport: Transport::Tcp,
+                ..self.clone()
+            },
+        }
+    }
+
+    /// Open a new connection, commands written to it are delimited by `framing`.
+    pub async fn connect(&self, framing: Framing) -> Result<(Reader, Writer)> {
+        match self.transport {
+            Transport::Tcp => {
+                let stream = TcpStream::connect(&self.host).await?;
+                match &self.tls {
+                    Some(tls) => {
+                        let (reader, writer) = tokio::io::split(tls.wrap(stream).await?);
+                        Ok((Box::new(reader), Box::new(writer)))
+                    }
+                    None => {
+                        let (reader, writer) = stream.into_split();
+                        Ok((Box::new(reader), Box::new(writer)))
+                    }
+                }
+            }
+            Transport::Udp => {
+                let addr = lookup_host(&self.host).await?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "host did not resolve")
+                })?;
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => ([0; 8], 0).into(),
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                let writer = MessageWriter::new(socket, framing, self.mtu);
+                Ok((Box::new(tokio::io::empty()), Box::new(writer)))
+            }
+            Transport::Unix => {
+                let path = self.host.strip_prefix("unix:").unwrap_or(&self.host);
+                let stream = UnixStream::connect(path).await?;
+                match &self.tls {
+                    Some(tls) => {
+                        let (reader, writer) = tokio::io::split(tls.wrap(stream).await?);
+                        Ok((Box::new(reader), Box::new(writer)))
+                    }
+                    None => {
+                        let (reader, writer) = stream.into_split();
+                        Ok((Box::new(reader), Box::new(writer)))
+                    }
+                }
+            }
+            Transport::WebSocket => {
+                let url = websocket::url(&self.host, self.tls.is_some());
+                let stream = TcpStream::connect(websocket::address(&url)?).await?;
+                match &self.tls {
+                    Some(tls) => {
+                        let stream = tls.wrap(stream).await?;
+                        websocket::connect(&url, stream, framing, self.ws_text).await
+                    }
+                    None => websocket::connect(&url, stream, framing, self.ws_text).await,
+                }
+            }
+        }
+    }
+}
+
+/// Something that takes whole commands, a message at a time.
+pub trait MessageSink {
+    /// Send one message, made up of whole commands.
+    fn poll_send(&mut self, cx: &mut Context<'_>, message: &[u8]) -> Poll<io::Result<()>>;
+
+    fn poll_flush(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl MessageSink for UdpSocket {
+    fn poll_send(&mut self, cx: &mut Context<'_>, message: &[u8]) -> Poll<io::Result<()>> {
+        UdpSocket::poll_send(self, cx, message).map_ok(|_| ())
+    }
+}
+
+/// Packs commands into messages of at most `max` bytes, without ever
+/// splitting a command over two of them.
+///
+/// Commands are only told apart by `framing` while more than a message is
+/// pending. A flush sends everything, as writers flush between commands and
+/// preambles do not follow the framing of set commands.
+pub struct MessageWriter<S> {
+    sink: S,
+    framing: Framing,
+    max: usize,
+    pending: Vec<u8>,
+}
+
+impl<S: MessageSink> MessageWriter<S> {
+    pub fn new(sink: S, framing: Framing, max: usize) -> Self {
+        Self {
+            sink,
+            framing,
+            max,
+            pending: Vec::with_capacity(max * 2),
+        }
+    }
+
+    /// Send the whole commands that fit in one message, returns whether
+    /// there were any. With `all` a pending tail that fits in one message
+    /// goes out as it is.
+    fn poll_send_message(&mut self, cx: &mut Context<'_>, all: bool) -> Poll<io::Result<bool>> {
+        let window = &self.pending[..self.pending.len().min(self.max)];
+        let len = match all && self.pending.len() <= self.max {
+            true => self.pending.len(),
+            false => self.framing.whole(window),
+        };
+        if len == 0 {
+            if self.pending.len() >= self.max {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "a command does not fit in one message",
+                )));
+            }
+            return Poll::Ready(Ok(false));
+        }
+        ready!(self.sink.poll_send(cx, &self.pending[..len]))?;
+        self.pending.drain(..len);
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<S: MessageSink + Unpin> AsyncWrite for MessageWriter<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.pending.len() >= this.max {
+            ready!(this.poll_send_message(cx, false))?;
+        }
+        let n = buf.len().min(this.max * 2 - this.pending.len());
+        this.pending.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Sends everything that is pending.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while ready!(this.poll_send_message(cx, true))? {}
+        this.sink.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while ready!(this.poll_send_message(cx, true))? {}
+        this.sink.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{server, CanvasSize, Framebuffer, Protocol};
+
+    #[tokio::test]
+    async fn test_datagrams_keep_commands_whole() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let mut writer = MessageWriter::new(client, Framing::Lines, 32);
+
+        writer
+            .write_all(b"PX 1 2 AB\nPX 10 20 ABCDEF\nPX 100 200 AB\nPX 3")
+            .await
+            .unwrap();
+        writer.write_all(b" 4 CD\n").await.unwrap();
+        writer.flush().await.unwrap();
+
+        let mut buf = [0; 64];
+        for expected in [
+            &b"PX 1 2 AB\nPX 10 20 ABCDEF\n"[..],
+            b"PX 100 200 AB\nPX 3 4 CD\n",
+        ] {
+            let n = server.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_sends_commands_of_other_framing() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let mut writer = MessageWriter::new(client, Framing::Fixed(10), 32);
+
+        writer.write_all(b"SIZE\n").await.unwrap();
+        writer.flush().await.unwrap();
+
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"SIZE\n");
+    }
+
+    #[tokio::test]
+    async fn test_unix_preamble() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tsunami.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let canvas = Framebuffer::new(&CanvasSize { x: 6, y: 4 });
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut reader = tokio::io::BufReader::new(reader);
+            server::handle(Protocol::Plaintext, &canvas, &mut reader, &mut writer).await
+        });
+
+        let host = format!("unix:{}", path.display());
+        let endpoint = Endpoint {
+            transport: Transport::Tcp.for_host(&host),
+            ..Endpoint::tcp(&host)
+        };
+        let size = Protocol::Plaintext
+            .probe(&endpoint, 0, std::time::Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(size, CanvasSize { x: 6, y: 4 });
+    }
+
+    #[test]
+    fn test_framing_whole() {
+        assert_eq!(Framing::Lines.whole(b"PX 1 1\nPX 2"), 7);
+        assert_eq!(Framing::Lines.whole(b"PX 1"), 0);
+        assert_eq!(Framing::Fixed(8).whole(&[0; 20]), 16);
+    }
+}
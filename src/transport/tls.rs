@@ -0,0 +1,106 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::{Error, Result};
+
+/// How to wrap connections in TLS, shared by all workers.
+#[derive(Clone)]
+pub struct Tls {
+    connector: TlsConnector,
+    name: ServerName<'static>,
+}
+
+impl Tls {
+    /// Trust the certificates in `ca`, or else the Mozilla roots, and expect
+    /// the server to be `sni`, or else the name in `host`.
+    pub fn new(host: &str, ca: Option<&Path>, sni: Option<&str>) -> Result<Tls> {
+        let mut roots = RootCertStore::empty();
+        match ca {
+            Some(path) => {
+                let invalid = |e: &dyn fmt::Display| {
+                    Error::InvalidConfig(format!("CA bundle {}: {}", path.display(), e))
+                };
+                for cert in CertificateDer::pem_file_iter(path).map_err(|e| invalid(&e))? {
+                    roots
+                        .add(cert.map_err(|e| invalid(&e))?)
+                        .map_err(|e| invalid(&e))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = sni.unwrap_or_else(|| host_name(host));
+        let name = ServerName::try_from(name.to_string()).map_err(|_| {
+            Error::InvalidConfig(format!("{} is not a valid TLS server name", name))
+        })?;
+        Ok(Tls {
+            connector: TlsConnector::from(Arc::new(config)),
+            name,
+        })
+    }
+
+    /// Run the handshake on a fresh connection.
+    pub async fn wrap<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<TlsStream<S>> {
+        self.connector
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(|e| Error::TlsHandshake(e.to_string()))
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls").field("name", &self.name).finish()
+    }
+}
+
+/// The name part of `host`, without scheme, port or path.
+fn host_name(host: &str) -> &str {
+    let authority = host.rsplit("://").next().unwrap_or(host);
+    let authority = authority.split('/').next().unwrap_or(authority);
+    let name = match authority.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => authority,
+    };
+    name.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(host_name("pixelflut.example:1337"), "pixelflut.example");
+        assert_eq!(host_name("wss://pixelflut.example/ws"), "pixelflut.example");
+        assert_eq!(host_name("[::1]:1337"), "::1");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = tokio::io::AsyncWriteExt::write_all(&mut socket, b"SIZE 6 4\n").await;
+        });
+
+        let tls = Tls::new(&addr.to_string(), None, Some("localhost")).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(matches!(
+            tls.wrap(stream).await,
+            Err(Error::TlsHandshake(_))
+        ));
+    }
+}
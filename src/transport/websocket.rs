@@ -0,0 +1,160 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{http::Uri, Bytes, Message, Utf8Bytes},
+    WebSocketStream,
+};
+
+use super::{MessageSink, MessageWriter, Reader, Writer};
+use crate::{Error, Framing, Result};
+
+/// Batch commands into messages of up to 64 KiB, servers take them whole.
+const MESSAGE_SIZE: usize = 64 * 1024;
+
+/// The full url for `host`, hosts without a scheme get one in front.
+pub fn url(host: &str, tls: bool) -> String {
+    match (host.contains("://"), tls) {
+        (true, _) => host.to_string(),
+        (false, false) => format!("ws://{}", host),
+        (false, true) => format!("wss://{}", host),
+    }
+}
+
+/// The address to open the underlying connection to.
+pub fn address(url: &str) -> Result<String> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| Error::InvalidConfig(format!("{} is not a websocket url: {}", url, e)))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| Error::InvalidConfig(format!("{} has no host", url)))?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("wss") => 443,
+        _ => 80,
+    });
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Upgrade `stream` to a websocket connection to `url`.
+pub async fn connect<S>(
+    url: &str,
+    stream: S,
+    framing: Framing,
+    text: bool,
+) -> Result<(Reader, Writer)>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (stream, _) = client_async(url, stream).await.map_err(io::Error::other)?;
+    let (sink, stream) = stream.split();
+    let reader = MessageReader {
+        stream,
+        buf: Bytes::new(),
+    };
+    let writer = MessageWriter::new(MessageSender { sink, text }, framing, MESSAGE_SIZE);
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+/// Sends commands as text or binary messages.
+struct MessageSender<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+    text: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MessageSink for MessageSender<S> {
+    fn poll_send(&mut self, cx: &mut Context<'_>, message: &[u8]) -> Poll<io::Result<()>> {
+        ready!(self.sink.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+        let payload = Bytes::copy_from_slice(message);
+        let message = match self.text {
+            true => Message::Text(Utf8Bytes::try_from(payload).map_err(io::Error::other)?),
+            false => Message::Binary(payload),
+        };
+        Poll::Ready(
+            self.sink
+                .start_send_unpin(message)
+                .map_err(io::Error::other),
+        )
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sink.poll_flush_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sink.poll_close_unpin(cx).map_err(io::Error::other)
+    }
+}
+
+/// Reads the payload of text and binary messages as one stream of bytes.
+struct MessageReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    buf: Bytes,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MessageReader<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.buf.is_empty() {
+            match ready!(self.stream.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => self.buf = data,
+                Some(Ok(Message::Text(text))) => self.buf = text.into(),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // pings get answered by the library
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+            }
+        }
+        let n = self.buf.len().min(out.remaining());
+        out.put_slice(&self.buf[..n]);
+        self.buf = self.buf.slice(n..);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = accept_async(socket).await.unwrap();
+            let message = stream.next().await.unwrap().unwrap();
+            stream.send(Message::text("SIZE 6 4\n")).await.unwrap();
+            message
+        });
+
+        let url = url(&addr.to_string(), false);
+        let stream = TcpStream::connect(address(&url).unwrap()).await.unwrap();
+        let (reader, mut writer) = connect(&url, stream, Framing::Lines, true).await.unwrap();
+        writer.write_all(b"SIZE\nPX 1 2 AB\n").await.unwrap();
+        writer.flush().await.unwrap();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await.unwrap();
+
+        assert_eq!(line, "SIZE 6 4\n");
+        assert_eq!(server.await.unwrap(), Message::text("SIZE\nPX 1 2 AB\n"));
+    }
+}
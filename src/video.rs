@@ -0,0 +1,175 @@
+use std::{path::Path, process::Stdio, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, BufReader},
+    process::{Child, ChildStdout, Command},
+    sync::watch,
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{Error, Frame, Result, SharedFrame};
+
+/// How much of the end of ffmpeg's error output is kept for error messages.
+const STDERR_TAIL: usize = 4096;
+
+/// Raw RGB frames decoded by an `ffmpeg` child process.
+pub struct Video {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    /// Drains stderr as ffmpeg writes it, so a full pipe never blocks it
+    stderr: Option<JoinHandle<String>>,
+    pub width: u16,
+    pub height: u16,
+    buf: Vec<u8>,
+}
+
+impl Video {
+    /// Ask `ffprobe` for the size of the first video stream.
+    pub async fn probe(path: &Path) -> Result<(u16, u16)> {
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=width,height",
+                "-of",
+                "csv=s=x:p=0",
+            ])
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| Error::FFmpegError(format!("could not start ffprobe: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::FFmpegError(format!(
+                "ffprobe failed on {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let size = stdout.lines().next().unwrap_or_default().trim();
+        size.split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .ok_or_else(|| {
+                Error::FFmpegError(format!("ffprobe returned an invalid size '{}'", size))
+            })
+    }
+
+    /// Start decoding `path`, scaled to `width` x `height` at `fps` frames per second.
+    pub fn open(path: &Path, width: u16, height: u16, fps: u32) -> Result<Self> {
+        let mut child = Command::new("ffmpeg")
+            .args(["-nostdin", "-loglevel", "error", "-i"])
+            .arg(path)
+            .args([
+                "-vf",
+                &format!("scale={}:{}", width, height),
+                "-r",
+                &fps.to_string(),
+                "-f",
+                "rawvideo",
+                "-pix_fmt",
+                "rgb24",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::FFmpegError(format!("could not start ffmpeg: {}", e)))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        Ok(Self {
+            child,
+            stdout: BufReader::new(stdout),
+            stderr: Some(tokio::spawn(read_tail(stderr))),
+            width,
+            height,
+            buf: vec![0; width as usize * height as usize * 3],
+        })
+    }
+
+    /// Read the next frame, `None` once the video has ended.
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match self.stdout.read_exact(&mut self.buf).await {
+            Ok(_) => Frame::from_rgb(self.width as u32, self.height as u32, &self.buf).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.finish().await?;
+                Ok(None)
+            }
+            Err(e) => Err(Error::FFmpegError(format!("reading frames failed: {}", e))),
+        }
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        let status = self
+            .child
+            .wait()
+            .await
+            .map_err(|e| Error::FFmpegError(e.to_string()))?;
+        if status.success() {
+            return Ok(());
+        }
+        let stderr = match self.stderr.take() {
+            Some(task) => task.await.unwrap_or_default(),
+            None => String::new(),
+        };
+        Err(Error::FFmpegError(format!(
+            "ffmpeg exited with {}: {}",
+            status,
+            stderr.trim()
+        )))
+    }
+
+    /// Publish frames to `tx` at `fps` until the video ends or nobody is listening.
+    pub async fn play(
+        mut self,
+        tx: watch::Sender<Option<Arc<SharedFrame>>>,
+        fps: u32,
+    ) -> Result<()> {
+        let mut ticker = interval(Duration::from_secs_f64(1.0 / fps.max(1) as f64));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        while let Some(frame) = self.next_frame().await? {
+            ticker.tick().await;
+            if tx.send(Some(Arc::new(SharedFrame::new(frame)))).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read `pipe` to its end, keeping only the last `STDERR_TAIL` bytes.
+async fn read_tail<R: AsyncRead + Unpin>(mut pipe: R) -> String {
+    let mut tail = Vec::new();
+    let mut buf = [0; 1024];
+    while let Ok(n) = pipe.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        tail.extend_from_slice(&buf[..n]);
+        if tail.len() > STDERR_TAIL {
+            tail.drain(..tail.len() - STDERR_TAIL);
+        }
+    }
+    String::from_utf8_lossy(&tail).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_tail() {
+        let mut output = vec![b'x'; 3 * STDERR_TAIL];
+        output.extend_from_slice(b"\nError opening input file\n");
+
+        let tail = read_tail(&output[..]).await;
+        assert_eq!(tail.len(), STDERR_TAIL);
+        assert!(tail.ends_with("\nError opening input file\n"));
+    }
+}